@@ -0,0 +1,361 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono;
+use toml;
+
+use errors::*;
+use squirrel::{Codec, GcSchedule, RetentionPolicy, UnchangedWrites, DEFAULT_KEYFRAME_INTERVAL};
+
+pub(crate) const PROJECT_CONFIG: &str = ".squirrel.toml";
+
+/// Settings as read from a single source. Anything left out falls through
+/// to the next source down: the command line, then `.squirrel.toml` in the
+/// watched directory, then the user's `squirrel/config.toml`, then the
+/// defaults.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Where snapshots and the event log are kept, relative to the watched directory
+    pub stash: Option<PathBuf>,
+    /// How long to wait for a file to settle before snapshotting it, in milliseconds
+    pub debounce: Option<u64>,
+    /// Where events are journalled; only "sqlite" for now
+    pub backend: Option<String>,
+    pub compression: Option<String>,
+    pub keyframe_interval: Option<usize>,
+    /// Files bigger than this many KiB have their events recorded, but aren't snapshotted
+    pub max_file_size: Option<u64>,
+    /// What to do when a file is saved without its contents changing: "skip" it, or record a "touch"
+    pub unchanged_writes: Option<String>,
+    pub ignore_dotfiles: Option<bool>,
+    /// Globs (in `.gitignore` syntax) of files to back up even if they'd otherwise be ignored
+    pub include: Option<Vec<String>>,
+    /// Globs (in `.gitignore` syntax) of files never to back up
+    pub exclude: Option<Vec<String>>,
+    pub ignore_files: IgnoreFilesConfig,
+    pub retention: RetentionConfig,
+}
+
+/// Which sources of ignore rules to follow
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct IgnoreFilesConfig {
+    /// `.gitignore` in each directory
+    pub gitignore: Option<bool>,
+    /// `.ignore` in each directory, as used by ripgrep and friends
+    pub ignore: Option<bool>,
+    /// `.squirrelignore` in each directory
+    pub squirrelignore: Option<bool>,
+    /// `.git/info/exclude` in the repository the watched directory is in
+    pub git_exclude: Option<bool>,
+    /// git's `core.excludesFile`, or `$XDG_CONFIG_HOME/git/ignore`
+    pub global: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    /// How often the daemon removes old snapshots, in minutes; never if unset
+    pub gc_interval: Option<u64>,
+    /// In hours
+    pub keep_all_for: Option<i64>,
+    /// In hours
+    pub hourly_for: Option<i64>,
+    /// In days
+    pub daily_for: Option<i64>,
+    /// In MiB
+    pub max_size: Option<u64>,
+}
+
+impl Config {
+    fn defaults() -> Config {
+        let retention = RetentionPolicy::default();
+        Config {
+            stash: Some(PathBuf::from(".backup")),
+            debounce: Some(1000),
+            backend: Some("sqlite".to_owned()),
+            compression: Some(format!("{}", Codec::Gzip)),
+            keyframe_interval: Some(DEFAULT_KEYFRAME_INTERVAL),
+            max_file_size: None,
+            unchanged_writes: Some(format!("{}", UnchangedWrites::Skip)),
+            ignore_dotfiles: Some(true),
+            include: Some(Vec::new()),
+            exclude: Some(Vec::new()),
+            ignore_files: IgnoreFilesConfig {
+                gitignore: Some(true),
+                ignore: Some(true),
+                squirrelignore: Some(true),
+                git_exclude: Some(true),
+                global: Some(true),
+            },
+            retention: RetentionConfig {
+                gc_interval: None,
+                keep_all_for: Some(retention.keep_all_for.num_hours()),
+                hourly_for: Some(retention.hourly_for.num_hours()),
+                daily_for: Some(retention.daily_for.num_days()),
+                max_size: None,
+            },
+        }
+    }
+
+    /// Takes each setting from `over` if it has one, and from `self` if not.
+    /// Include and exclude globs from every source are kept.
+    fn overridden_by(self, over: Config) -> Config {
+        Config {
+            stash: over.stash.or(self.stash),
+            debounce: over.debounce.or(self.debounce),
+            backend: over.backend.or(self.backend),
+            compression: over.compression.or(self.compression),
+            keyframe_interval: over.keyframe_interval.or(self.keyframe_interval),
+            max_file_size: over.max_file_size.or(self.max_file_size),
+            unchanged_writes: over.unchanged_writes.or(self.unchanged_writes),
+            ignore_dotfiles: over.ignore_dotfiles.or(self.ignore_dotfiles),
+            include: concatenated(self.include, over.include),
+            exclude: concatenated(self.exclude, over.exclude),
+            ignore_files: IgnoreFilesConfig {
+                gitignore: over.ignore_files.gitignore.or(self.ignore_files.gitignore),
+                ignore: over.ignore_files.ignore.or(self.ignore_files.ignore),
+                squirrelignore: over.ignore_files.squirrelignore.or(self.ignore_files.squirrelignore),
+                git_exclude: over.ignore_files.git_exclude.or(self.ignore_files.git_exclude),
+                global: over.ignore_files.global.or(self.ignore_files.global),
+            },
+            retention: RetentionConfig {
+                gc_interval: over.retention.gc_interval.or(self.retention.gc_interval),
+                keep_all_for: over.retention.keep_all_for.or(self.retention.keep_all_for),
+                hourly_for: over.retention.hourly_for.or(self.retention.hourly_for),
+                daily_for: over.retention.daily_for.or(self.retention.daily_for),
+                max_size: over.retention.max_size.or(self.retention.max_size),
+            },
+        }
+    }
+}
+
+fn concatenated(under: Option<Vec<String>>, over: Option<Vec<String>>) -> Option<Vec<String>> {
+    match (under, over) {
+        (Some(mut under), Some(over)) => {
+            under.extend(over);
+            Some(under)
+        }
+        (under, over) => over.or(under),
+    }
+}
+
+/// Which files `PathFilter` should let through, and which ignore files it
+/// should take notice of
+#[derive(Debug, Clone)]
+pub(crate) struct FilterSettings {
+    pub ignore_dotfiles: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub gitignore: bool,
+    pub ignore: bool,
+    pub squirrelignore: bool,
+    pub git_exclude: bool,
+    pub global_gitignore: bool,
+}
+
+/// The settings squirrel runs with, once every source has been taken into account
+#[derive(Debug)]
+pub(crate) struct Settings {
+    pub stash_path: PathBuf,
+    pub debounce: Duration,
+    pub codec: Codec,
+    pub keyframe_interval: usize,
+    pub max_file_size: Option<u64>,
+    pub unchanged_writes: UnchangedWrites,
+    pub filter: FilterSettings,
+    pub retention: RetentionPolicy,
+    pub gc_interval: Option<Duration>,
+    /// The configuration files that were read, least important first
+    pub sources: Vec<PathBuf>,
+    /// Everything merged together, as it would be written in a config file
+    pub resolved: Config,
+}
+
+impl Settings {
+    pub(crate) fn gc_schedule(&self) -> Option<GcSchedule> {
+        self.gc_interval.map(|interval| {
+            GcSchedule {
+                interval: interval,
+                policy: self.retention.clone(),
+            }
+        })
+    }
+}
+
+fn invalid(source: &str, detail: String) -> Error {
+    ErrorKind::InvalidConfig(source.to_owned(), detail).into()
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("squirrel").join("config.toml"))
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    toml::from_str(&contents).map_err(|e| {
+        invalid(&path.to_string_lossy(), format!("{}", e))
+    })
+}
+
+/// Reads the configuration files and resolves them, along with the options
+/// given on the command line, into the settings to run with
+pub(crate) fn load(watched_dir: &Path, command_line: Config) -> Result<Settings> {
+    let mut config = Config::defaults();
+    let mut sources = Vec::new();
+
+    let candidates = user_config_path().into_iter().chain(
+        Some(watched_dir.join(PROJECT_CONFIG)),
+    );
+    for path in candidates {
+        if path.is_file() {
+            config = config.overridden_by(read_config(&path)?);
+            sources.push(path);
+        }
+    }
+
+    resolve(watched_dir, config.overridden_by(command_line), sources)
+}
+
+fn resolve(watched_dir: &Path, config: Config, sources: Vec<PathBuf>) -> Result<Settings> {
+    // Everything has a default, bar the settings that are off unless given
+    let stash = config.stash.clone().unwrap();
+    let backend = config.backend.clone().unwrap();
+    if backend != "sqlite" {
+        return Err(invalid(
+            "backend",
+            format!("unknown backend '{}' (expected 'sqlite')", backend),
+        ));
+    }
+    let codec = Codec::from_str(config.compression.as_ref().unwrap())?;
+    let unchanged_writes = config.unchanged_writes.clone().unwrap();
+    let unchanged_writes = UnchangedWrites::from_str(&unchanged_writes).ok_or_else(|| {
+        invalid(
+            "unchanged-writes",
+            format!("unknown setting '{}' (expected 'skip' or 'touch')", unchanged_writes),
+        )
+    })?;
+
+    let retention = &config.retention;
+    let policy = RetentionPolicy {
+        keep_all_for: chrono::Duration::hours(retention.keep_all_for.unwrap()),
+        hourly_for: chrono::Duration::hours(retention.hourly_for.unwrap()),
+        daily_for: chrono::Duration::days(retention.daily_for.unwrap()),
+        max_size: retention.max_size.map(|mib| mib * 1024 * 1024),
+    };
+
+    Ok(Settings {
+        stash_path: watched_dir.join(stash),
+        debounce: Duration::from_millis(config.debounce.unwrap()),
+        codec: codec,
+        keyframe_interval: config.keyframe_interval.unwrap(),
+        max_file_size: config.max_file_size.map(|kib| kib * 1024),
+        unchanged_writes: unchanged_writes,
+        filter: FilterSettings {
+            ignore_dotfiles: config.ignore_dotfiles.unwrap(),
+            include: config.include.clone().unwrap(),
+            exclude: config.exclude.clone().unwrap(),
+            gitignore: config.ignore_files.gitignore.unwrap(),
+            ignore: config.ignore_files.ignore.unwrap(),
+            squirrelignore: config.ignore_files.squirrelignore.unwrap(),
+            git_exclude: config.ignore_files.git_exclude.unwrap(),
+            global_gitignore: config.ignore_files.global.unwrap(),
+        },
+        retention: policy,
+        gc_interval: retention.gc_interval.map(|minutes| Duration::from_secs(minutes * 60)),
+        sources: sources,
+        resolved: config,
+    })
+}
+
+/// Prints the settings squirrel would run with in the current directory
+pub(crate) fn show(settings: &Settings) -> Result<()> {
+    if settings.sources.is_empty() {
+        println!("# No configuration files found; using the defaults");
+    }
+    for source in &settings.sources {
+        println!("# Read from {}", source.to_string_lossy());
+    }
+    let resolved = toml::to_string(&settings.resolved).map_err(|e| {
+        invalid("resolved settings", format!("{}", e))
+    })?;
+    print!("{}", resolved);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+
+    use squirrel::UnchangedWrites;
+    use super::Config;
+
+    fn parse(toml: &str) -> Config {
+        ::toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let user = parse("stash = \"user\"\ndebounce = 200\nexclude = [\"*.log\"]\n[retention]\ndaily-for = 7\n");
+        let project = parse("stash = \"project\"\nexclude = [\"*.tmp\"]\n");
+        let command_line = Config {
+            debounce: Some(50),
+            include: Some(vec!["keep.log".to_owned()]),
+            ..Config::default()
+        };
+
+        let config = Config::defaults()
+            .overridden_by(user)
+            .overridden_by(project)
+            .overridden_by(command_line);
+
+        assert_eq!(config.stash, Some(PathBuf::from("project")));
+        assert_eq!(config.debounce, Some(50));
+        assert_eq!(config.retention.daily_for, Some(7));
+        assert_eq!(config.keyframe_interval, Config::defaults().keyframe_interval);
+        assert_eq!(config.exclude, Some(vec!["*.log".to_owned(), "*.tmp".to_owned()]));
+        assert_eq!(config.include, Some(vec!["keep.log".to_owned()]));
+    }
+
+    #[test]
+    fn project_config_is_read_from_the_watched_directory() {
+        let watched = TempDir::new("squirrel-config").unwrap();
+        File::create(watched.path().join(super::PROJECT_CONFIG))
+            .unwrap()
+            .write_all(b"stash = \"elsewhere\"\nmax-file-size = 2\nunchanged-writes = \"touch\"\nexclude = [\"*.log\"]\n")
+            .unwrap();
+
+        let settings = super::load(watched.path(), Config::default()).unwrap();
+        assert_eq!(settings.stash_path, watched.path().join("elsewhere"));
+        assert_eq!(settings.max_file_size, Some(2048));
+        assert_eq!(settings.unchanged_writes, UnchangedWrites::Touch);
+        assert_eq!(settings.filter.exclude, vec!["*.log".to_owned()]);
+        assert!(settings.sources.contains(&watched.path().join(super::PROJECT_CONFIG)));
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        assert!(::toml::from_str::<Config>("stsah = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn resolved_settings_can_be_read_back() {
+        let shown = ::toml::to_string(&Config::defaults()).unwrap();
+        let read: Config = ::toml::from_str(&shown).unwrap();
+        assert_eq!(read.stash, Config::defaults().stash);
+        assert_eq!(read.retention.hourly_for, Config::defaults().retention.hourly_for);
+    }
+}
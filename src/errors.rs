@@ -3,7 +3,71 @@ error_chain! {
     errors {
         EventJournallingError(detail: String) {
             description("unable to journal an event")
-            display("there was a problem recoring an event in the journal: {}", detail)
+            display("there was a problem recording an event in the journal: {}", detail)
+        }
+        InvalidTimestamp(timestamp: String) {
+            description("unable to understand a timestamp")
+            display("unable to understand '{}' as a point in time (try 'YYYY-MM-DD HH:MM')", timestamp)
+        }
+        NoSuchEvent(event_id: i64) {
+            description("no event with the given id")
+            display("there is no event with id {}", event_id)
+        }
+        NoSnapshot(event_id: i64) {
+            description("event has no snapshot")
+            display("event {} has no snapshot to restore", event_id)
+        }
+        NoMatchingEvent(path: String, timestamp: String) {
+            description("no recorded version of the file at the given time")
+            display("no recorded version of {} at {}", path, timestamp)
+        }
+        UnsavedChanges(path: String) {
+            description("file has changes which haven't been recorded")
+            display("{} has changes which haven't been recorded; use --force to overwrite it anyway", path)
+        }
+        UnsupportedSchemaVersion(found: i64, supported: i64) {
+            description("the event log was written by a newer version of squirrel")
+            display("the event log has schema version {}, but this version of squirrel only understands up to version {}; please upgrade squirrel", found, supported)
+        }
+        UnknownCodec(codec: String) {
+            description("unknown compression codec")
+            display("unknown compression '{}' (expected 'gzip' or 'none')", codec)
+        }
+        CorruptSnapshot(detail: String) {
+            description("a stored snapshot could not be read back")
+            display("a stored snapshot is damaged: {}", detail)
+        }
+        InvalidConfig(source: String, detail: String) {
+            description("invalid configuration")
+            display("invalid configuration in {}: {}", source, detail)
+        }
+        FileTooLarge(path: String, size: u64) {
+            description("file is too large to snapshot")
+            display("{} is too large to snapshot ({} bytes)", path, size)
+        }
+        AlreadyRunning(pid: u32) {
+            description("a daemon is already watching this directory")
+            display("squirrel is already watching this directory (pid {})", pid)
+        }
+        NotRunning {
+            description("no daemon is watching this directory")
+            display("squirrel isn't watching this directory")
+        }
+        DaemonDidNotStart(log: String) {
+            description("the daemon didn't start")
+            display("the daemon didn't start; see {} for why", log)
+        }
+        DaemonStillRunning(pid: u32) {
+            description("the daemon is still running")
+            display("the daemon (pid {}) is still running; stop it with `squirrel stop` first", pid)
+        }
+        ScheduledGcRunning(pid: u32) {
+            description("the daemon removes old snapshots on a schedule")
+            display("the daemon (pid {}) removes old snapshots on a schedule, which could remove the ones this needs; stop it with `squirrel stop` first", pid)
+        }
+        DamagedStash(problems: usize) {
+            description("problems were found in the stash")
+            display("found {} problems in the stash", problems)
         }
         NoCommand {
             description("no command was specified")
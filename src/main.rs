@@ -3,29 +3,76 @@
 extern crate chrono;
 #[macro_use]
 extern crate clap;
+extern crate diff;
 extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
+extern crate flate2;
 extern crate glob;
 extern crate futures;
 extern crate ignore;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate notify;
-extern crate rand;
 extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha1;
+#[cfg(test)]
+extern crate tempdir;
+extern crate toml;
 
 use std::path::PathBuf;
 
+use clap::ArgMatches;
+
 mod errors;
 use errors::*;
 mod squirrel;
 
 mod path_filter;
+mod config;
 
 
 quick_main!(run);
 
+/// The settings given on the command line, which take precedence over
+/// anything in a configuration file
+fn command_line_config(matches: &ArgMatches) -> config::Config {
+    let mut config = config::Config {
+        compression: matches.value_of("compression").map(|c| c.to_owned()),
+        ..config::Config::default()
+    };
+    if let Some(globs) = matches.values_of("include") {
+        config.include = Some(globs.map(|g| g.to_owned()).collect());
+    }
+    if let Some(globs) = matches.values_of("exclude") {
+        config.exclude = Some(globs.map(|g| g.to_owned()).collect());
+    }
+    if matches.is_present("keyframe_interval") {
+        config.keyframe_interval = Some(value_t_or_exit!(matches, "keyframe_interval", usize));
+    }
+    if matches.is_present("gc_interval") {
+        config.retention.gc_interval = Some(value_t_or_exit!(matches, "gc_interval", u64));
+    }
+    if matches.is_present("keep_all_for") {
+        config.retention.keep_all_for = Some(value_t_or_exit!(matches, "keep_all_for", i64));
+    }
+    if matches.is_present("hourly_for") {
+        config.retention.hourly_for = Some(value_t_or_exit!(matches, "hourly_for", i64));
+    }
+    if matches.is_present("daily_for") {
+        config.retention.daily_for = Some(value_t_or_exit!(matches, "daily_for", i64));
+    }
+    if matches.is_present("max_size") {
+        config.retention.max_size = Some(value_t_or_exit!(matches, "max_size", u64));
+    }
+    config
+}
+
 fn run() -> Result<()> {
     let matches = clap_app!(code_squirrel =>
         (version: crate_version!())
@@ -33,10 +80,76 @@ fn run() -> Result<()> {
         (about: crate_description!())
         (@subcommand daemon =>
             (about: "run the daemon to monitor a directory")
+            (@arg foreground: -f --foreground "Stay attached to the terminal rather than running in the background")
+            (@arg include: --include +takes_value +multiple number_of_values(1) "Back up files matching this glob (in .gitignore syntax), even if they'd otherwise be ignored")
+            (@arg exclude: --exclude +takes_value +multiple number_of_values(1) "Never back up files matching this glob (in .gitignore syntax)")
+            (@arg compression: --compression +takes_value "How to compress new snapshots: gzip (the default) or none")
+            (@arg keyframe_interval: --("keyframe-interval") +takes_value "Store every Nth snapshot of a file whole, and the rest as deltas against the one before (default: 10; 1 turns deltas off)")
+            (@arg gc_interval: --("gc-interval") +takes_value "Remove old snapshots every this many minutes")
+            (@arg keep_all_for: --("keep-all-for") +takes_value "When removing old snapshots, keep every snapshot from the last this many hours (default: 6)")
+            (@arg hourly_for: --("hourly-for") +takes_value "... then one snapshot per file per hour, up to this many hours old (default: 24)")
+            (@arg daily_for: --("daily-for") +takes_value "... then one snapshot per file per day, up to this many days old (default: 30)")
+            (@arg max_size: --("max-size") +takes_value "... then remove the oldest snapshots until they take up at most this many MiB")
+        )
+        (@subcommand status =>
+            (about: "report whether the daemon is watching this directory, and what it has recorded")
+        )
+        (@subcommand stop =>
+            (about: "stop the daemon watching this directory, once it has recorded any changes in flight")
         )
         (@subcommand show =>
             (about: "show revisions to files matching GLOB")
             (@arg GLOB: +required "The glob to match against")
+            (@arg long: -l --long "Also show the size, permissions, modification time and content hash recorded for each file")
+        )
+        (@subcommand log =>
+            (about: "show the history of a file, following it through renames")
+            (@arg PATH: +required "The path of the file, relative to the watched directory")
+        )
+        (@subcommand restore =>
+            (about: "restore a file from a recorded snapshot")
+            (@arg ID: required_unless[path] conflicts_with[path] "The id of the event to restore (as listed by show)")
+            (@arg path: --path +takes_value requires[at] "The file to restore")
+            (@arg at: --at +takes_value requires[path] "The point in time to restore the file to (YYYY-MM-DD HH:MM, UTC)")
+            (@arg force: -f --force "Overwrite the file even if it has changes which haven't been recorded")
+        )
+        (@subcommand diff =>
+            (about: "show how a file changed between two snapshots, or since a snapshot")
+            (@arg FROM: +required "The id of the event to compare from")
+            (@arg TO: "The id of the event to compare to (defaults to the file as it is now)")
+            (@arg stat: --stat "Only summarise the number of lines changed")
+        )
+        (@subcommand gc =>
+            (about: "remove old snapshots")
+            (@arg keep_all_for: --("keep-all-for") +takes_value "Keep every snapshot from the last this many hours (default: 6)")
+            (@arg hourly_for: --("hourly-for") +takes_value "... then one snapshot per file per hour, up to this many hours old (default: 24)")
+            (@arg daily_for: --("daily-for") +takes_value "... then one snapshot per file per day, up to this many days old (default: 30)")
+            (@arg max_size: --("max-size") +takes_value "... then remove the oldest snapshots until they take up at most this many MiB")
+            (@arg dry_run: -n --("dry-run") "Only report what would be removed")
+        )
+        (@subcommand fsck =>
+            (about: "check the stash for missing, damaged or leftover snapshots, and a damaged event log")
+            (@arg repair: --repair "Remove leftovers, quarantine damaged snapshots and mark events whose snapshot is gone as pruned (the daemon must be stopped)")
+        )
+        (@subcommand check_ignore =>
+            (name: "check-ignore")
+            (about: "explain whether a file would be backed up, and which rule decided it")
+            (@arg PATH: +required "The path of the file, relative to the watched directory")
+            (@arg include: --include +takes_value +multiple number_of_values(1) "As for the daemon")
+            (@arg exclude: --exclude +takes_value +multiple number_of_values(1) "As for the daemon")
+        )
+        (@subcommand config =>
+            (about: "inspect squirrel's configuration")
+            (@subcommand show =>
+                (about: "print the settings squirrel would use here, once .squirrel.toml, the user's config.toml and the defaults are combined")
+            )
+        )
+        (@subcommand checkout =>
+            (about: "bring back every tracked file as it was at a point in time")
+            (@arg at: --at +takes_value +required "The point in time to check out (YYYY-MM-DD HH:MM, UTC)")
+            (@arg into: --into +takes_value "Write the files into DIR rather than over the working directory")
+            (@arg force: -f --force "Overwrite files even if they have changes which haven't been recorded")
+            (@arg yes: -y --yes "Don't ask for confirmation before overwriting files")
         )
     ).get_matches();
 
@@ -45,16 +158,101 @@ fn run() -> Result<()> {
     let watched_dir = PathBuf::from(".").canonicalize().expect(
         "Unable to determine the path to the current directory",
     );
-    let stash_path = watched_dir.join(".backup");
+    let command_line = match matches.subcommand() {
+        (_, Some(matches)) => command_line_config(matches),
+        _ => config::Config::default(),
+    };
+    let settings = config::load(&watched_dir, command_line)?;
+    let stash_path = settings.stash_path.clone();
+
+    if let Some(matches) = matches.subcommand_matches("config") {
+        if matches.subcommand_matches("show").is_some() {
+            return config::show(&settings);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check-ignore") {
+        let path = PathBuf::from(matches.value_of("PATH").unwrap());
+        return path_filter::check_ignore(&watched_dir, &settings, &path);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        return squirrel::run_daemon(&watched_dir, &settings, matches.is_present("foreground"));
+    }
+
+    if matches.subcommand_matches("status").is_some() {
+        return squirrel::show_status(&stash_path);
+    }
+
+    if matches.subcommand_matches("stop").is_some() {
+        return squirrel::stop_daemon(&stash_path);
+    }
 
-    if let Some(_) = matches.subcommand_matches("daemon") {
-        return squirrel::run_squirrel(&watched_dir, &stash_path);
+    if let Some(matches) = matches.subcommand_matches("gc") {
+        return squirrel::run_gc(
+            &stash_path,
+            &settings.retention,
+            matches.is_present("dry_run"),
+        );
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        return squirrel::run_fsck(&stash_path, matches.is_present("repair"));
     }
 
     if let Some(matches) = matches.subcommand_matches("show") {
         let glob = matches.value_of("GLOB").unwrap();
         let glob = glob::Pattern::new(&glob)?;
-        return squirrel::list_snapshots(&stash_path, glob);
+        return squirrel::list_snapshots(&stash_path, glob, matches.is_present("long"));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("log") {
+        let path = PathBuf::from(matches.value_of("PATH").unwrap());
+        return squirrel::show_log(&watched_dir, &stash_path, &path);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("restore") {
+        let target = if let Some(path) = matches.value_of("path") {
+            let at = squirrel::EventTime::parse(matches.value_of("at").unwrap())?;
+            squirrel::RestoreTarget::PathAt(PathBuf::from(path), at)
+        } else {
+            squirrel::RestoreTarget::Event(value_t_or_exit!(matches, "ID", i64))
+        };
+        return squirrel::restore_snapshot(
+            &watched_dir,
+            &settings,
+            target,
+            matches.is_present("force"),
+        );
+    }
+
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        let from = value_t_or_exit!(matches, "FROM", i64);
+        let to = if matches.is_present("TO") {
+            Some(value_t_or_exit!(matches, "TO", i64))
+        } else {
+            None
+        };
+        return squirrel::diff_snapshots(
+            &watched_dir,
+            &stash_path,
+            from,
+            to,
+            matches.is_present("stat"),
+        );
+    }
+
+    if let Some(matches) = matches.subcommand_matches("checkout") {
+        let at = squirrel::EventTime::parse(matches.value_of("at").unwrap())?;
+        let into = matches.value_of("into").map(PathBuf::from);
+        return squirrel::checkout(
+            &watched_dir,
+            &settings,
+            at,
+            into.as_ref().map(|p| p.as_path()),
+            matches.is_present("force"),
+            matches.is_present("yes"),
+        );
     }
 
     println!("{}", matches.usage());
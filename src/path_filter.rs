@@ -1,63 +1,342 @@
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::{PathBuf, Path, Component};
-use ignore::gitignore::GitignoreBuilder;
+use std::rc::Rc;
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use config::{FilterSettings, Settings};
 use errors;
 
-pub(crate) fn new(base_path: &Path, stash_path: &Path) -> errors::Result<PathFilter> {
+/// Files of ignore rules, in `.gitignore` syntax, that apply to the directory
+/// they're in and everything below it. Later files take precedence, so a
+/// `.squirrelignore` can both add to and overrule a `.gitignore`.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore", ".squirrelignore"];
+
+/// The ignore files in each directory that the settings ask to be followed
+fn ignore_files(settings: &FilterSettings) -> Vec<&'static str> {
+    let wanted = [settings.gitignore, settings.ignore, settings.squirrelignore];
+    IGNORE_FILES
+        .iter()
+        .zip(wanted.iter())
+        .filter(|&(_, wanted)| *wanted)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// The top of the git repository the watched directory is in, if any
+fn repository_root(base_path: &Path) -> Option<&Path> {
+    let mut dir = Some(base_path);
+    while let Some(candidate) = dir {
+        if candidate.join(".git").is_dir() {
+            return Some(candidate);
+        }
+        dir = candidate.parent();
+    }
+    None
+}
+
+/// Rules from outside the watched directory: `.git/info/exclude`, then git's
+/// global excludes file. These are read once, when the filter is created.
+fn repository_matchers(base_path: &Path, settings: &FilterSettings) -> errors::Result<Vec<Gitignore>> {
+    let mut matchers = Vec::new();
+
+    if settings.git_exclude {
+        if let Some(root) = repository_root(base_path) {
+            let exclude = root.join(".git").join("info").join("exclude");
+            if exclude.is_file() {
+                let mut builder = GitignoreBuilder::new(root);
+                builder.add(exclude);
+                matchers.push(builder.build()?);
+            }
+        }
+    }
+
+    if settings.global_gitignore {
+        let (global, error) = Gitignore::global();
+        if let Some(error) = error {
+            warn!("problem reading the global gitignore file: {}", error);
+        }
+        matchers.push(global);
+    }
+
+    Ok(matchers)
+}
+
+/// A rule which matched a path: a glob from the configuration, or a line of
+/// an ignore file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rule {
+    pub pattern: String,
+    /// The ignore file and line the rule is on, if it came from one
+    pub source: Option<(PathBuf, usize)>,
+}
+
+/// ignore's `Glob` knows which file it came from, but not which line, so
+/// find the last line with the same pattern on it (the last one wins)
+fn line_of(file: &Path, pattern: &str) -> Option<usize> {
+    let lines = BufReader::new(File::open(file).ok()?).lines();
+    lines
+        .enumerate()
+        .filter(|&(_, ref line)| {
+            line.as_ref().map(|l| l.trim_right() == pattern).unwrap_or(false)
+        })
+        .map(|(i, _)| i + 1)
+        .last()
+}
+
+impl Rule {
+    fn from_glob(glob: &Glob) -> Rule {
+        Rule {
+            pattern: glob.original().to_owned(),
+            source: glob.from().and_then(|file| {
+                line_of(file, glob.original()).map(|line| (file.to_owned(), line))
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some((ref file, line)) => {
+                write!(f, "'{}' ({}:{})", self.pattern, file.to_string_lossy(), line)
+            }
+            None => write!(f, "'{}' (from the configuration)", self.pattern),
+        }
+    }
+}
+
+/// Whether a path should be backed up, and why
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Decision {
+    /// Nothing says the path should be ignored
+    Allowed,
+    /// An include glob, or a negated line in an ignore file, asks for it
+    Included(Rule),
+    OutOfScope,
+    InStash,
+    Dotted,
+    /// An exclude glob from the configuration
+    Excluded(Rule),
+    /// A line in an ignore file
+    Ignored(Rule),
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        match self {
+            &Decision::Allowed | &Decision::Included(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Decision::Allowed => write!(f, "backed up"),
+            &Decision::Included(ref rule) => write!(f, "backed up: included by {}", rule),
+            &Decision::OutOfScope => write!(f, "ignored: outside the watched directory"),
+            &Decision::InStash => write!(f, "ignored: inside the stash"),
+            &Decision::Dotted => write!(f, "ignored: a dotted file"),
+            &Decision::Excluded(ref rule) => write!(f, "ignored: excluded by {}", rule),
+            &Decision::Ignored(ref rule) => write!(f, "ignored: ignored by {}", rule),
+        }
+    }
+}
+
+/// The part of an include glob before its first wildcard, which anything it
+/// matches must lie under. Globs without a `/` (bar a trailing one) match at
+/// any depth, as in `.gitignore`, so could match anywhere; they give `None`.
+fn literal_prefix(glob: &str) -> Option<PathBuf> {
+    let glob = glob.trim_right_matches('/');
+    if !glob.contains('/') {
+        return None;
+    }
+
+    let is_literal = |part: &&str| !part.contains(|c: char| "*?[{\\".contains(c));
+    Some(
+        glob.trim_left_matches('/')
+            .split('/')
+            .take_while(is_literal)
+            .collect(),
+    )
+}
+
+pub(crate) fn new(
+    base_path: &Path,
+    stash_path: &Path,
+    settings: &FilterSettings,
+) -> errors::Result<PathFilter> {
     let base_path = PathBuf::from(base_path).canonicalize()?;
-    let stash_path = PathBuf::from(stash_path).canonicalize()?;
+    // The stash won't exist yet if the daemon has never run
+    let stash_path = stash_path.canonicalize().unwrap_or_else(
+        |_| stash_path.to_owned(),
+    );
+
+    // Includes come last, so that they win over excludes
+    let mut overrides = GitignoreBuilder::new(&base_path);
+    for glob in &settings.exclude {
+        overrides.add_line(None, glob)?;
+    }
+    for glob in &settings.include {
+        overrides.add_line(None, &format!("!{}", glob))?;
+    }
+
+    let repository_matchers = repository_matchers(&base_path, &settings)?;
+
     Ok(PathFilter {
         base_path: base_path,
         stash_path: stash_path,
+        ignore_dotfiles: settings.ignore_dotfiles,
+        overrides: overrides.build()?,
+        include_prefixes: settings.include.iter().map(|g| literal_prefix(g)).collect(),
+        ignore_files: ignore_files(&settings),
+        matchers: RefCell::new(HashMap::new()),
+        repository_matchers: repository_matchers,
     })
 }
 
 pub(crate) struct PathFilter {
     base_path: PathBuf,
     stash_path: PathBuf,
+    ignore_dotfiles: bool,
+    // The include and exclude globs from the configuration
+    overrides: Gitignore,
+    // Where the files each include glob matches must lie, if it's limited
+    include_prefixes: Vec<Option<PathBuf>>,
+    ignore_files: Vec<&'static str>,
+    // The compiled ignore files of each directory seen so far
+    matchers: RefCell<HashMap<PathBuf, Rc<Gitignore>>>,
+    repository_matchers: Vec<Gitignore>,
 }
 
 impl PathFilter {
-    fn is_in_scope(&self, path: &Path) -> bool {
-        (!path.is_absolute()) || path.starts_with(&self.base_path)
-    }
-
-    fn ignored(&self, path: &Path) -> errors::Result<bool> {
-        let rel_path = if path.is_absolute() {
-            path.strip_prefix(&self.base_path)?
-        } else {
-            path
-        };
+    /// Works out where `path` (absolute, or relative to the base path) is
+    /// relative to the base path, without any `.` or `..` in it. Gives `None`
+    /// if it lies outside the base path, or is a symlink to somewhere that
+    /// does.
+    fn normalise(&self, path: &Path) -> Option<PathBuf> {
+        let path = self.base_path.join(path);
 
-        let mut builder = GitignoreBuilder::new(&self.base_path);
-        builder.add(".gitignore");
-
-        let mut ignore_path = self.base_path.clone();
-        for c in rel_path.components() {
+        let mut normalised = PathBuf::new();
+        for c in path.components() {
             match c {
-                Component::Normal(ref path_part) => ignore_path.push(path_part),
-                _ => {
-                    panic!(
-                        "Expecting a path witin {:?} but got {:?} - don't know how to check ignore status",
-                        self.base_path,
-                        rel_path
-                    )
+                Component::Prefix(_) | Component::RootDir | Component::Normal(_) => {
+                    normalised.push(c.as_os_str())
                 }
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !normalised.pop() {
+                        return None;
+                    }
+                }
+            }
+        }
+        let rel_path = normalised.strip_prefix(&self.base_path).ok()?.to_owned();
+
+        // The file system has the final say on where `..` and symlinks lead,
+        // for paths that still exist
+        if let Ok(real_path) = path.canonicalize() {
+            if !real_path.starts_with(&self.base_path) {
+                return None;
             }
+        }
+
+        Some(rel_path)
+    }
+
+    /// The rules from the ignore files in `dir`, which are only read the first
+    /// time they're needed
+    fn matcher_for(&self, dir: &Path) -> errors::Result<Rc<Gitignore>> {
+        if let Some(matcher) = self.matchers.borrow().get(dir) {
+            return Ok(matcher.clone());
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        for ignore_file in &self.ignore_files {
+            let ignore_path = dir.join(ignore_file);
             if ignore_path.is_file() {
-                break;
+                builder.add(ignore_path);
             }
+        }
+        let matcher = Rc::new(builder.build()?);
+
+        self.matchers.borrow_mut().insert(
+            dir.to_owned(),
+            matcher.clone(),
+        );
+        Ok(matcher)
+    }
 
-            builder.add(&ignore_path.join(".gitignore"));
+    /// Forgets the compiled ignore files that a change to `path` may have made
+    /// out of date. Must be told about every change, including those to paths
+    /// which are themselves filtered out.
+    pub fn notice_change(&self, path: &Path) {
+        let path = self.base_path.join(path);
+        let mut matchers = self.matchers.borrow_mut();
+
+        let is_ignore_file = path.file_name().map_or(false, |name| {
+            IGNORE_FILES.iter().any(|f| name == *f)
+        });
+        if is_ignore_file {
+            if let Some(dir) = path.parent() {
+                matchers.remove(dir);
+            }
         }
 
-        let built = builder.build()?;
+        // A directory may have been moved or removed, along with its ignore files
+        if !path.is_file() {
+            matchers.retain(|dir, _| !dir.starts_with(&path));
+        }
+    }
 
-        Ok(
-            built
-                .matched_path_or_any_parents(&path, path.is_dir())
-                .is_ignore(),
-        )
+    /// Checks the path against the ignore files in each directory from the
+    /// base path down to it, returning the rule that decided it, if any
+    fn ignore_files_match(&self, rel_path: &Path) -> errors::Result<Match<Rule>> {
+        let mut dirs = vec![self.base_path.clone()];
+        let mut dir = self.base_path.clone();
+        let mut components = rel_path.components();
+        // Every directory above the path, but not the path itself
+        components.next_back();
+        for c in components {
+            dir.push(c.as_os_str());
+            dirs.push(dir.clone());
+        }
+
+        let path = self.base_path.join(rel_path);
+        let is_dir = path.is_dir();
+
+        // Rules in deeper directories take precedence
+        for dir in dirs.iter().rev() {
+            let matcher = self.matcher_for(dir)?;
+            match matcher.matched_path_or_any_parents(&path, is_dir) {
+                Match::Ignore(glob) => return Ok(Match::Ignore(Rule::from_glob(glob))),
+                Match::Whitelist(glob) => return Ok(Match::Whitelist(Rule::from_glob(glob))),
+                Match::None => {}
+            }
+        }
+
+        for matcher in &self.repository_matchers {
+            // The global excludes file lives elsewhere, so it is given the path
+            // relative to the watched directory rather than an absolute one
+            let candidate = if path.starts_with(matcher.path()) {
+                path.as_path()
+            } else {
+                rel_path
+            };
+            match matcher.matched_path_or_any_parents(candidate, is_dir) {
+                Match::Ignore(glob) => return Ok(Match::Ignore(Rule::from_glob(glob))),
+                Match::Whitelist(glob) => return Ok(Match::Whitelist(Rule::from_glob(glob))),
+                Match::None => {}
+            }
+        }
+
+        Ok(Match::None)
     }
 
     fn is_stash_path(&self, path: &Path) -> bool {
@@ -79,28 +358,344 @@ impl PathFilter {
         false
     }
 
-    pub fn allow(&self, path: &Path) -> errors::Result<bool> {
-        if !self.is_in_scope(&path) {
-            debug!("ignoring {:?} because not in scope", path);
-            return Ok(false);
+    /// Whether files below a directory which isn't allowed itself might still
+    /// be allowed, because an include glob could match something in it
+    pub fn may_allow_within(&self, dir: &Path) -> bool {
+        let rel_path = match self.normalise(dir) {
+            Some(rel_path) => rel_path,
+            None => return false,
+        };
+        if self.is_stash_path(&self.base_path.join(&rel_path)) {
+            return false;
         }
 
+        self.include_prefixes.iter().any(|prefix| match prefix {
+            &Some(ref prefix) => prefix.starts_with(&rel_path) || rel_path.starts_with(prefix),
+            &None => true,
+        })
+    }
+
+    /// Runs a path through each of the filters in turn, returning the first
+    /// decision made about it
+    pub fn decide(&self, path: &Path) -> errors::Result<Decision> {
+        let rel_path = match self.normalise(&path) {
+            Some(rel_path) => rel_path,
+            None => return Ok(Decision::OutOfScope),
+        };
+        let path = self.base_path.join(&rel_path);
+
         if self.is_stash_path(&path) {
-            debug!("ignoring {:?} because within the stash", path);
-            return Ok(false);
+            return Ok(Decision::InStash);
+        }
+
+        match self.overrides.matched_path_or_any_parents(&path, path.is_dir()) {
+            Match::Whitelist(glob) => return Ok(Decision::Included(Rule::from_glob(glob))),
+            Match::Ignore(glob) => return Ok(Decision::Excluded(Rule::from_glob(glob))),
+            Match::None => {}
+        }
+
+        // An ignore file can explicitly ask for a dotted file to be tracked
+        let ignore_files_match = self.ignore_files_match(&rel_path)?;
+        if let Match::Whitelist(rule) = ignore_files_match {
+            return Ok(Decision::Included(rule));
+        }
+
+        if self.ignore_dotfiles && self.is_dotted(&rel_path) {
+            return Ok(Decision::Dotted);
+        }
+
+        if let Match::Ignore(rule) = ignore_files_match {
+            return Ok(Decision::Ignored(rule));
         }
 
-        if self.is_dotted(&path) {
-            debug!("ignoring {:?} because it's a dotted file", path);
-            return Ok(false);
+        Ok(Decision::Allowed)
+    }
+
+    pub fn allow(&self, path: &Path) -> errors::Result<bool> {
+        let decision = self.decide(&path)?;
+        debug!("{:?}: {}", path, decision);
+        Ok(decision.is_allowed())
+    }
+}
+
+/// Prints whether a path would be backed up, and which rule decided it
+pub(crate) fn check_ignore(watched_dir: &Path, settings: &Settings, path: &Path) -> errors::Result<()> {
+    let filter = new(&watched_dir, &settings.stash_path, &settings.filter)?;
+
+    println!("{}: {}", path.to_string_lossy(), filter.decide(&path)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Instant;
+
+    use tempdir::TempDir;
+
+    use config::FilterSettings;
+    use super::{Decision, Rule};
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn settings(include: &[&str], exclude: &[&str]) -> FilterSettings {
+        FilterSettings {
+            ignore_dotfiles: true,
+            include: include.iter().map(|g| g.to_string()).collect(),
+            exclude: exclude.iter().map(|g| g.to_string()).collect(),
+            gitignore: true,
+            ignore: true,
+            squirrelignore: true,
+            git_exclude: true,
+            // Keep the tests independent of whoever runs them
+            global_gitignore: false,
         }
+    }
+
+    fn base() -> TempDir {
+        let base = TempDir::new("squirrel-filter").unwrap();
+        fs::create_dir(base.path().join(".backup")).unwrap();
+        base
+    }
+
+    #[test]
+    fn squirrelignore_adds_to_and_overrules_gitignore() {
+        let base = base();
+        write(&base.path().join(".gitignore"), "*.tmp\n");
+        write(&base.path().join(".squirrelignore"), "*.log\n!keep.tmp\n!.env.example\n");
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        let allowed = |name: &str| filter.allow(&base.path().join(name)).unwrap();
+        assert!(allowed("main.rs"));
+        assert!(!allowed("scratch.tmp"));
+        assert!(!allowed("build.log"));
+        assert!(allowed("keep.tmp"));
+        assert!(allowed(".env.example"));
+        assert!(!allowed(".env"));
+    }
+
+    #[test]
+    fn configured_globs_win_over_ignore_files() {
+        let base = base();
+        write(&base.path().join(".squirrelignore"), "*.log\n");
+        let filter = super::new(
+            base.path(),
+            &base.path().join(".backup"),
+            &settings(&["wanted.log", ".backup/*"], &["secret*"]),
+        ).unwrap();
+
+        let allowed = |name: &str| filter.allow(&base.path().join(name)).unwrap();
+        assert!(allowed("wanted.log"));
+        assert!(!allowed("other.log"));
+        assert!(!allowed("secret.rs"));
+        assert!(!allowed(".backup/event-log.db"));
+    }
+
+    #[test]
+    fn decisions_name_the_deciding_rule() {
+        let base = base();
+        let gitignore = base.path().canonicalize().unwrap().join(".gitignore");
+        write(&gitignore, "# build output\ntarget/\n*.tmp\n");
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &["*.bak"])).unwrap();
+
+        let decide = |name: &str| filter.decide(&base.path().canonicalize().unwrap().join(name)).unwrap();
+        assert_eq!(
+            decide("scratch.tmp"),
+            Decision::Ignored(Rule {
+                pattern: "*.tmp".to_owned(),
+                source: Some((gitignore.clone(), 3)),
+            })
+        );
+        assert_eq!(
+            decide("old.bak"),
+            Decision::Excluded(Rule {
+                pattern: "*.bak".to_owned(),
+                source: None,
+            })
+        );
+        assert_eq!(decide(".hidden"), Decision::Dotted);
+        assert_eq!(decide(".backup/event-log.db"), Decision::InStash);
+        assert_eq!(decide("main.rs"), Decision::Allowed);
+    }
+
+    #[test]
+    fn changed_ignore_files_are_read_again() {
+        let base = base();
+        let dir = base.path().canonicalize().unwrap().join("src");
+        fs::create_dir(&dir).unwrap();
+        write(&dir.join(".gitignore"), "*.tmp\n");
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        assert!(!filter.allow(&dir.join("a.tmp")).unwrap());
+
+        write(&dir.join(".gitignore"), "*.log\n");
+        assert!(!filter.allow(&dir.join("a.tmp")).unwrap());
+        filter.notice_change(&dir.join(".gitignore"));
+        assert!(filter.allow(&dir.join("a.tmp")).unwrap());
+        assert!(!filter.allow(&dir.join("a.log")).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+        filter.notice_change(&dir);
+        fs::create_dir(&dir).unwrap();
+        assert!(filter.allow(&dir.join("a.log")).unwrap());
+    }
+
+    #[test]
+    fn deeper_ignore_files_take_precedence() {
+        let base = base();
+        let root = base.path().canonicalize().unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        write(&root.join(".gitignore"), "*.gen\n/top.rs\n");
+        write(&root.join("src").join(".gitignore"), "!keep.gen\n");
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        assert!(!filter.allow(&root.join("src").join("other.gen")).unwrap());
+        assert!(filter.allow(&root.join("src").join("keep.gen")).unwrap());
+        assert!(!filter.allow(&root.join("top.rs")).unwrap());
+        assert!(filter.allow(&root.join("src").join("top.rs")).unwrap());
+    }
+
+    #[test]
+    fn git_exclude_and_ignore_files_are_followed_unless_turned_off() {
+        let base = base();
+        let root = base.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join(".git").join("info")).unwrap();
+        write(&root.join(".git").join("info").join("exclude"), "*.bak\n");
+        write(&root.join(".ignore"), "*.cache\n");
+
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+        assert!(!filter.allow(&root.join("old.bak")).unwrap());
+        assert!(!filter.allow(&root.join("data.cache")).unwrap());
+
+        let mut turned_off = settings(&[], &[]);
+        turned_off.git_exclude = false;
+        turned_off.ignore = false;
+        let filter = super::new(base.path(), &base.path().join(".backup"), &turned_off).unwrap();
+        assert!(filter.allow(&root.join("old.bak")).unwrap());
+        assert!(filter.allow(&root.join("data.cache")).unwrap());
+    }
 
-        if self.ignored(&path)? {
-            debug!("ignoring {:?} because ignored by .gitignore", path);
-            return Ok(false);
+    #[test]
+    fn parent_and_current_directory_segments_are_resolved() {
+        let base = base();
+        let root = base.path().canonicalize().unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        write(&root.join("a.rs"), "");
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        assert_eq!(filter.decide(Path::new("./src/../a.rs")).unwrap(), Decision::Allowed);
+        assert_eq!(filter.decide(&root.join("src/../a.rs")).unwrap(), Decision::Allowed);
+        assert_eq!(filter.decide(Path::new("src/../.backup/x")).unwrap(), Decision::InStash);
+        assert_eq!(filter.decide(Path::new("../elsewhere.rs")).unwrap(), Decision::OutOfScope);
+        assert_eq!(filter.decide(Path::new("src/../../elsewhere.rs")).unwrap(), Decision::OutOfScope);
+        assert_eq!(filter.decide(Path::new("../../../../../../../..")).unwrap(), Decision::OutOfScope);
+    }
+
+    #[test]
+    fn absolute_paths_outside_the_base_are_out_of_scope() {
+        let base = base();
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        assert_eq!(filter.decide(Path::new("/")).unwrap(), Decision::OutOfScope);
+        assert_eq!(filter.decide(Path::new("/etc/passwd")).unwrap(), Decision::OutOfScope);
+        let sibling = base.path().with_file_name("not-the-base");
+        assert_eq!(filter.decide(&sibling.join("a.rs")).unwrap(), Decision::OutOfScope);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_base_are_out_of_scope() {
+        use std::os::unix::fs::symlink;
+
+        let base = base();
+        let outside = TempDir::new("squirrel-outside").unwrap();
+        let root = base.path().canonicalize().unwrap();
+        write(&outside.path().join("secret.rs"), "");
+        write(&root.join("a.rs"), "");
+        symlink(outside.path().join("secret.rs"), root.join("out.rs")).unwrap();
+        symlink(outside.path(), root.join("out")).unwrap();
+        symlink(root.join("a.rs"), root.join("in.rs")).unwrap();
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+
+        assert_eq!(filter.decide(Path::new("out.rs")).unwrap(), Decision::OutOfScope);
+        assert_eq!(filter.decide(Path::new("out/secret.rs")).unwrap(), Decision::OutOfScope);
+        assert_eq!(filter.decide(Path::new("in.rs")).unwrap(), Decision::Allowed);
+    }
+
+    #[test]
+    fn only_directories_an_include_could_match_within_are_descended_into() {
+        let base = base();
+        let root = base.path().canonicalize().unwrap();
+        write(&root.join(".gitignore"), "target/\nnode_modules/\n");
+        let filter = super::new(
+            base.path(),
+            &base.path().join(".backup"),
+            &settings(&["target/keep/*.rs", "/docs/**/*.md"], &[]),
+        ).unwrap();
+
+        assert!(filter.may_allow_within(&root.join("target")));
+        assert!(filter.may_allow_within(&root.join("target/keep")));
+        assert!(filter.may_allow_within(&root.join("target/keep/nested")));
+        assert!(filter.may_allow_within(&root.join("docs/api")));
+        assert!(!filter.may_allow_within(&root.join("target/debug")));
+        assert!(!filter.may_allow_within(&root.join("node_modules")));
+        assert!(!filter.may_allow_within(&root.join("targets")));
+        assert!(!filter.may_allow_within(&root.join(".backup")));
+
+        // A glob without a directory in it matches at any depth
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&["*.log"], &[])).unwrap();
+        assert!(filter.may_allow_within(&root.join("node_modules")));
+
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
+        assert!(!filter.may_allow_within(&root.join("target")));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture filter_throughput`
+    #[test]
+    #[ignore]
+    fn filter_throughput() {
+        let base = base();
+        let mut dir = base.path().canonicalize().unwrap();
+        write(&dir.join(".gitignore"), "target/\n*.o\n");
+        let mut paths = Vec::new();
+        for depth in 0..6 {
+            dir.push(format!("level{}", depth));
+            fs::create_dir(&dir).unwrap();
+            write(&dir.join(".gitignore"), &format!("*.tmp{}\n!keep.tmp{}\n", depth, depth));
+            for n in 0..20 {
+                let path = dir.join(format!("file{}.rs", n));
+                write(&path, "");
+                paths.push(path);
+            }
         }
+        let filter = super::new(base.path(), &base.path().join(".backup"), &settings(&[], &[])).unwrap();
 
-        return Ok(true);
+        let events = 20_000;
+        let rate = |cached: bool| {
+            let start = Instant::now();
+            for path in paths.iter().cycle().take(events) {
+                if !cached {
+                    // Forgets every compiled ignore file, as if nothing were cached
+                    filter.notice_change(Path::new(""));
+                }
+                filter.allow(path).unwrap();
+            }
+            let elapsed = start.elapsed();
+            events as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9)
+        };
 
+        let uncached = rate(false);
+        let cached = rate(true);
+        println!(
+            "{} events: {:.0} events/s uncached, {:.0} events/s cached ({:.1}x)",
+            events,
+            uncached,
+            cached,
+            cached / uncached
+        );
     }
 }
@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use super::event::{file_stat, EventTime, EventType, FileEvent, FileStat, Snapshot};
+use super::gc;
+use super::journal::{JournalReader, sqlite_journal, PagedJournalQuery};
+use super::restore::{differs_from_recorded, read_contents, set_mode, write_contents};
+use super::snapshot_store::{self, SnapshotStore};
+use super::squirrel;
+
+use config::Settings;
+use errors::*;
+
+/// The recorded state of every tracked file. Files which are known to have
+/// existed, but which couldn't be snapshotted, have no `Snapshot`.
+pub(super) type Tree = BTreeMap<PathBuf, Option<Snapshot>>;
+
+/// What the journal last recorded about a file
+#[derive(Debug, Clone)]
+pub(super) struct RecordedFile {
+    pub snapshot: Option<Snapshot>,
+    pub stat: Option<FileStat>,
+}
+
+pub(super) type RecordedTree = BTreeMap<PathBuf, RecordedFile>;
+
+fn remove_under<V>(tree: &mut BTreeMap<PathBuf, V>, prefix: &Path) -> Vec<(PathBuf, V)> {
+    let removed: Vec<PathBuf> = tree.keys()
+        .filter(|p| p.starts_with(prefix))
+        .cloned()
+        .collect();
+
+    removed
+        .into_iter()
+        .map(|p| {
+            let recorded = tree.remove(&p).unwrap();
+            (p, recorded)
+        })
+        .collect()
+}
+
+/// Replays the journal from the beginning to rebuild what was known about
+/// each file at `timestamp` (or now, if no time is given).
+pub(super) fn recorded_at<'a, J>(journal: &'a J, timestamp: Option<&EventTime>) -> Result<RecordedTree>
+where
+    J: JournalReader<'a>,
+{
+    let mut tree = RecordedTree::new();
+
+    let mut forwards = journal.forwards()?;
+    for event in forwards.next_page()? {
+        let event = event?;
+        if timestamp.map_or(false, |t| event.timestamp > *t) {
+            break;
+        }
+        let recorded = RecordedFile {
+            snapshot: event.snapshot.clone(),
+            stat: event.stat.clone(),
+        };
+
+        match event.event_type {
+            EventType::Create |
+            EventType::Update |
+            EventType::Restore |
+            EventType::Baseline |
+            EventType::Touch => {
+                if let Some(path) = event.path() {
+                    tree.insert(path.to_owned(), recorded);
+                }
+            }
+            EventType::Rename => {
+                if let (&Some(ref from), &Some(ref to)) = (&event.before_path, &event.after_path) {
+                    // Directory renames only show up as a single event, so
+                    // carry everything underneath along with them
+                    for (path, moved) in remove_under(&mut tree, from) {
+                        let rest = path.strip_prefix(from).unwrap();
+                        let moved_to = if rest.as_os_str().is_empty() {
+                            to.clone()
+                        } else {
+                            to.join(rest)
+                        };
+                        tree.insert(moved_to, moved);
+                    }
+                    if event.snapshot.is_some() {
+                        tree.insert(to.clone(), recorded);
+                    }
+                }
+            }
+            EventType::Remove => {
+                if let Some(path) = event.path() {
+                    remove_under(&mut tree, path);
+                }
+            }
+        }
+    }
+
+    Ok(tree)
+}
+
+/// The snapshot of each file as it was at `timestamp` (or as it is now, if no
+/// time is given)
+pub(super) fn tree_at<'a, J>(journal: &'a J, timestamp: Option<&EventTime>) -> Result<Tree>
+where
+    J: JournalReader<'a>,
+{
+    Ok(
+        recorded_at(journal, timestamp)?
+            .into_iter()
+            .map(|(path, recorded)| (path, recorded.snapshot))
+            .collect(),
+    )
+}
+
+pub fn checkout(
+    watched_dir: &Path,
+    settings: &Settings,
+    timestamp: EventTime,
+    into: Option<&Path>,
+    force: bool,
+    assume_yes: bool,
+) -> Result<()> {
+    let stash_path = &settings.stash_path;
+    let journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+    let tree = recorded_at(&journal, Some(&timestamp))?;
+
+    match into {
+        Some(dir) => write_tree(&store, &tree, dir),
+        None => {
+            gc::ensure_no_scheduled_gc(&stash_path)?;
+            let current = tree_at(&journal, None)?;
+            checkout_in_place(
+                watched_dir,
+                settings,
+                &store,
+                &tree,
+                &current,
+                force,
+                assume_yes,
+            )
+        }
+    }
+}
+
+fn warn_no_snapshot(path: &Path) {
+    println!(
+        "warning: no snapshot of {} was recorded; skipping it",
+        path.to_string_lossy()
+    );
+}
+
+fn recorded_mode(recorded: &RecordedFile) -> Option<u32> {
+    recorded.stat.as_ref().and_then(|stat| stat.mode)
+}
+
+fn write_tree(store: &SnapshotStore, tree: &RecordedTree, dir: &Path) -> Result<()> {
+    let mut written = 0;
+    for (path, recorded) in tree {
+        match recorded.snapshot {
+            Some(ref snapshot) => {
+                let destination = dir.join(path);
+                write_contents(&destination, &store.read(snapshot)?)?;
+                if let Some(mode) = recorded_mode(recorded) {
+                    set_mode(&destination, mode)?;
+                }
+                written += 1;
+            }
+            None => warn_no_snapshot(path),
+        }
+    }
+
+    println!("Wrote {} files to {}", written, dir.to_string_lossy());
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    let stdin = io::stdin();
+    stdin.lock().read_line(&mut answer)?;
+
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Removes the directories above a removed file which it leaves empty, up to
+/// the watched directory itself
+fn remove_empty_parents(watched_dir: &Path, removed: &Path) {
+    let mut dir = removed.parent();
+    while let Some(parent) = dir {
+        if parent == watched_dir || !parent.starts_with(watched_dir) || fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+/// A file to bring back as it was
+struct Change<'a> {
+    path: &'a PathBuf,
+    snapshot: &'a Snapshot,
+    mode: Option<u32>,
+    /// Whether only its permissions differ
+    same_contents: bool,
+}
+
+fn checkout_in_place(
+    watched_dir: &Path,
+    settings: &Settings,
+    store: &SnapshotStore,
+    tree: &RecordedTree,
+    current: &Tree,
+    force: bool,
+    assume_yes: bool,
+) -> Result<()> {
+    let mut changes = Vec::new();
+    let mut unsaved = Vec::new();
+    for (path, recorded) in tree {
+        let snapshot = match recorded.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => {
+                warn_no_snapshot(path);
+                continue;
+            }
+        };
+
+        let live_file = watched_dir.join(path);
+        let mode = recorded_mode(recorded);
+        let same_contents = live_file.is_file() && read_contents(&live_file)? == store.read(snapshot)?;
+        let same_mode = mode.is_none() || file_stat(&live_file).and_then(|stat| stat.mode) == mode;
+        if same_contents && same_mode {
+            continue;
+        }
+
+        let recorded = current.get(path).and_then(|s| s.as_ref());
+        if !same_contents && differs_from_recorded(&store, &live_file, recorded)? {
+            unsaved.push(path);
+        }
+        changes.push(Change {
+            path: path,
+            snapshot: snapshot,
+            mode: mode,
+            same_contents: same_contents,
+        });
+    }
+
+    // Files which didn't exist at that time, and would otherwise be left
+    // lying around in the rebuilt tree
+    let mut removals = Vec::new();
+    for (path, recorded) in current {
+        let live_file = watched_dir.join(path);
+        if tree.contains_key(path) || !live_file.is_file() {
+            continue;
+        }
+        if differs_from_recorded(&store, &live_file, recorded.as_ref())? {
+            unsaved.push(path);
+        }
+        removals.push(path);
+    }
+
+    if changes.is_empty() && removals.is_empty() {
+        println!("All tracked files already match that point in time");
+        return Ok(());
+    }
+
+    if !unsaved.is_empty() && !force {
+        let paths: Vec<String> = unsaved
+            .iter()
+            .map(|p| String::from(p.to_string_lossy()))
+            .collect();
+        return Err(ErrorKind::UnsavedChanges(paths.join(", ")).into());
+    }
+
+    let prompt = format!(
+        "This will overwrite {} files and remove {} files in {}. Continue?",
+        changes.len(),
+        removals.len(),
+        watched_dir.to_string_lossy()
+    );
+    if !assume_yes && !confirm(&prompt)? {
+        println!("Nothing was changed");
+        return Ok(());
+    }
+
+    let mut squirrel = squirrel::configured(&settings, sqlite_journal::new(&settings.stash_path)?)?;
+    for change in &changes {
+        let path = change.path;
+        if unsaved.contains(&path) {
+            // Keep hold of what's being overwritten, so the checkout can be undone
+            squirrel.dispatch_event(FileEvent::Write(path.clone()))?;
+        }
+        let live_file = watched_dir.join(path);
+        if !change.same_contents {
+            write_contents(&live_file, &store.read(change.snapshot)?)?;
+        }
+        if let Some(mode) = change.mode {
+            set_mode(&live_file, mode)?;
+        }
+        squirrel.record_restore(path, change.snapshot)?;
+    }
+    for &path in &removals {
+        if unsaved.contains(&path) {
+            squirrel.dispatch_event(FileEvent::Write(path.clone()))?;
+        }
+        let live_file = watched_dir.join(path);
+        fs::remove_file(&live_file)?;
+        remove_empty_parents(watched_dir, &live_file);
+        squirrel.dispatch_event(FileEvent::Remove(path.clone()))?;
+    }
+    println!("Restored {} files, and removed {}", changes.len(), removals.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use config::{self, Config, Settings};
+    use errors::*;
+    use squirrel::event::*;
+    use squirrel::journal::{sqlite_journal, Journal, JournalReader, PagedJournalQuery};
+    use squirrel::squirrel;
+    use super::Tree;
+
+    fn at(time: &str) -> EventTime {
+        EventTime::parse(time).unwrap()
+    }
+
+    fn snapshot(n: usize) -> Option<Snapshot> {
+        Some(Snapshot::from_str(&format!("{:040x}", n), Codec::Gzip))
+    }
+
+    fn tree_at(stash: &Path, time: &str) -> Vec<(PathBuf, Option<Snapshot>)> {
+        let journal = sqlite_journal::new(stash).unwrap();
+        let tree: Tree = super::tree_at(&journal, Some(&at(time))).unwrap();
+        tree.into_iter().collect()
+    }
+
+    fn p(path: &str) -> PathBuf {
+        PathBuf::from(path)
+    }
+
+    #[test]
+    fn the_journal_is_replayed_up_to_the_time_given() {
+        let stash = TempDir::new("squirrel-checkout").unwrap();
+        let mut journal = sqlite_journal::new(stash.path()).unwrap();
+        let events = vec![
+            new_event(EventType::Baseline, at("2017-10-21 10:00"), snapshot(1), None, Some(p("src/a.rs"))),
+            new_event(EventType::Create, at("2017-10-21 10:00"), snapshot(2), None, Some(p("src/b.rs"))),
+            new_event(EventType::Update, at("2017-10-21 11:00"), snapshot(3), None, Some(p("src/a.rs"))),
+            new_event(EventType::Rename, at("2017-10-21 12:00"), snapshot(4), Some(p("src/c.rs")), Some(p("src/b.rs"))),
+            new_event(EventType::Rename, at("2017-10-21 13:00"), None, Some(p("lib")), Some(p("src"))),
+            new_event(EventType::Remove, at("2017-10-21 14:00"), None, None, Some(p("lib/a.rs"))),
+            new_event(EventType::Create, at("2017-10-21 15:00"), None, None, Some(p("big.bin"))),
+            new_event(EventType::Remove, at("2017-10-21 16:00"), None, None, Some(p("lib"))),
+        ];
+        for event in events {
+            journal.journal(event).unwrap();
+        }
+
+        assert_eq!(
+            tree_at(stash.path(), "2017-10-21 10:30"),
+            vec![(p("src/a.rs"), snapshot(1)), (p("src/b.rs"), snapshot(2))]
+        );
+        assert_eq!(
+            tree_at(stash.path(), "2017-10-21 12:00"),
+            vec![(p("src/a.rs"), snapshot(3)), (p("src/c.rs"), snapshot(4))]
+        );
+        // Everything in a renamed directory moves with it
+        assert_eq!(
+            tree_at(stash.path(), "2017-10-21 13:30"),
+            vec![(p("lib/a.rs"), snapshot(3)), (p("lib/c.rs"), snapshot(4))]
+        );
+        // Files which couldn't be snapshotted are still known of
+        assert_eq!(
+            tree_at(stash.path(), "2017-10-21 15:30"),
+            vec![(p("big.bin"), None), (p("lib/c.rs"), snapshot(4))]
+        );
+        // Everything in a removed directory goes with it
+        assert_eq!(tree_at(stash.path(), "2017-10-21 16:30"), vec![(p("big.bin"), None)]);
+    }
+
+    fn write(path: &Path, contents: &[u8]) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn read(path: &Path) -> Vec<u8> {
+        super::read_contents(path).unwrap()
+    }
+
+    fn settings(watched: &Path) -> Settings {
+        let settings = config::load(watched, Config::default()).unwrap();
+        fs::create_dir_all(&settings.stash_path).unwrap();
+        settings
+    }
+
+    /// Records `a.txt` as "one", then, after the time returned, as "two",
+    /// along with a new file `sub/b.txt`
+    fn record_history(watched: &Path, settings: &Settings) -> EventTime {
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut squirrel = squirrel::configured(settings, journal).unwrap();
+
+        let a = watched.join("a.txt");
+        write(&a, b"one");
+        squirrel.dispatch_event(FileEvent::Create(a.clone())).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let before = get_timestamp_now();
+        thread::sleep(Duration::from_millis(10));
+
+        write(&a, b"two");
+        squirrel.dispatch_event(FileEvent::Write(a)).unwrap();
+        let b = watched.join("sub").join("b.txt");
+        write(&b, b"new");
+        squirrel.dispatch_event(FileEvent::Create(b)).unwrap();
+        before
+    }
+
+    fn recorded_types(settings: &Settings) -> Vec<EventType> {
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut forwards = journal.forwards().unwrap();
+        forwards.next_page().unwrap().map(|e| e.unwrap().event_type).collect()
+    }
+
+    #[test]
+    fn checking_out_in_place_overwrites_and_removes_files() {
+        let watched = TempDir::new("squirrel-checkout").unwrap();
+        let watched = watched.path();
+        let settings = settings(watched);
+        let before = record_history(watched, &settings);
+
+        super::checkout(watched, &settings, before, None, false, true).unwrap();
+
+        assert_eq!(read(&watched.join("a.txt")), b"one");
+        assert!(!watched.join("sub").exists());
+        assert_eq!(
+            recorded_types(&settings),
+            vec![
+                EventType::Create,
+                EventType::Update,
+                EventType::Create,
+                EventType::Restore,
+                EventType::Remove,
+            ]
+        );
+    }
+
+    #[test]
+    fn checking_out_in_place_refuses_to_lose_unsaved_changes() {
+        let watched = TempDir::new("squirrel-checkout").unwrap();
+        let watched = watched.path();
+        let settings = settings(watched);
+        let before = record_history(watched, &settings);
+        write(&watched.join("sub").join("b.txt"), b"unsaved");
+
+        match super::checkout(watched, &settings, before, None, false, true) {
+            Err(Error(ErrorKind::UnsavedChanges(ref paths), _)) => assert!(paths.contains("b.txt")),
+            other => panic!("expected the checkout to be refused, got {:?}", other),
+        }
+        assert_eq!(read(&watched.join("a.txt")), b"two");
+        assert_eq!(read(&watched.join("sub").join("b.txt")), b"unsaved");
+        assert_eq!(recorded_types(&settings).len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checking_out_in_place_puts_permissions_back() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let watched = TempDir::new("squirrel-checkout").unwrap();
+        let watched = watched.path();
+        let settings = settings(watched);
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut squirrel = squirrel::configured(&settings, journal).unwrap();
+
+        let script = watched.join("run.sh");
+        write(&script, b"#!/bin/sh\n");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        squirrel.dispatch_event(FileEvent::Create(script.clone())).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let before = get_timestamp_now();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+
+        super::checkout(watched, &settings, before, None, false, true).unwrap();
+
+        assert_eq!(file_stat(&script).unwrap().mode, Some(0o755));
+        assert_eq!(recorded_types(&settings), vec![EventType::Create, EventType::Restore]);
+    }
+}
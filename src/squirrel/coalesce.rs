@@ -0,0 +1,361 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::event::FileEvent;
+
+/// How long a file that's been moved aside is given to come back before
+/// that's taken at face value. notify passes on all the changes it saw in
+/// one go, so the new version turns up almost straight away, if at all.
+const SAVE_WINDOW_MS: u64 = 1000;
+
+/// A change waiting to be passed on
+#[derive(Debug)]
+struct Queued {
+    event: FileEvent,
+    /// Set while a file that's been moved aside is waiting for its new
+    /// version; everything after it waits too, so that nothing is recorded
+    /// out of order
+    until: Option<Instant>,
+}
+
+/// Turns the changes editors make when they save a file into a single
+/// `Write` of that file.
+///
+/// By the time notify has debounced them, most saves look like a `Write`,
+/// or like the file being created, when a new version has been renamed over
+/// it (JetBrains IDEs, gedit) or written in place of one that was removed. A
+/// `Create` of a file that's already known to exist is passed on as a
+/// `Write`. Emacs renames the original to a backup (`main.rs~`) before
+/// writing the new version, so a file renamed to a scratch name is held
+/// back for a moment in case a new version turns up. Changes to scratch
+/// files themselves (swap files, lock files, backups and temporary files)
+/// are dropped.
+pub(super) struct Coalescer {
+    window: Duration,
+    /// Files known to exist, from the tracked files found at startup and
+    /// the changes passed on since
+    known: HashSet<PathBuf>,
+    queue: VecDeque<Queued>,
+}
+
+pub(super) fn new(known: Vec<PathBuf>) -> Coalescer {
+    Coalescer {
+        window: Duration::from_millis(SAVE_WINDOW_MS),
+        known: known.into_iter().collect(),
+        queue: VecDeque::new(),
+    }
+}
+
+/// Whether the file is one editors only use while saving, or while a file
+/// is open, rather than one anybody would want to restore
+fn is_scratch(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+
+    let vim_swap = name.starts_with('.') && match name.rfind(".sw") {
+        Some(i) => {
+            let suffix = &name[i + 3..];
+            suffix == "px" || (suffix.len() == 1 && suffix >= "a" && suffix <= "p")
+        }
+        None => false,
+    };
+
+    // Backups kept by vim and emacs
+    name.ends_with('~') ||
+        // Vim checks it can write to a directory by creating this
+        name == "4913" ||
+        vim_swap ||
+        // Emacs' lock files and auto-saves
+        name.starts_with(".#") ||
+        (name.len() > 1 && name.starts_with('#') && name.ends_with('#')) ||
+        name.ends_with("___jb_tmp___") ||
+        name.ends_with("___jb_old___") ||
+        // Written by GIO, for gedit and other GTK applications
+        name.starts_with(".goutputstream-")
+}
+
+impl Coalescer {
+    /// Takes in the next change, and gives back whichever changes are ready
+    /// to be recorded
+    pub(super) fn push(&mut self, event: FileEvent, now: Instant) -> Vec<FileEvent> {
+        match event {
+            FileEvent::Write(p) => self.written(p, false),
+            FileEvent::Create(p) => self.written(p, true),
+            FileEvent::Remove(p) => {
+                if !is_scratch(&p) {
+                    self.known.retain(|known| !known.starts_with(&p));
+                    self.enqueue(FileEvent::Remove(p), None);
+                }
+            }
+            FileEvent::Rename(from, to) => {
+                match (is_scratch(&from), is_scratch(&to)) {
+                    (true, true) => (),
+                    (false, true) => {
+                        // Moved aside, perhaps to make way for a new version
+                        self.known.remove(&from);
+                        let until = now + self.window;
+                        self.enqueue(FileEvent::Rename(from, to), Some(until));
+                    }
+                    // A new version written alongside and moved into place
+                    (true, false) => self.written(to, true),
+                    (false, false) => {
+                        let moved: Vec<PathBuf> = self.known
+                            .iter()
+                            .filter(|known| known.starts_with(&from))
+                            .cloned()
+                            .collect();
+                        for path in moved {
+                            self.known.remove(&path);
+                            self.known.insert(to.join(path.strip_prefix(&from).unwrap()));
+                        }
+                        self.known.insert(to.clone());
+                        self.enqueue(FileEvent::Rename(from, to), None);
+                    }
+                }
+            }
+            // The notices notify sends ahead of the debounced changes;
+            // there's nothing in them to record
+            FileEvent::UnknownEvent => (),
+        }
+
+        self.expired(now)
+    }
+
+    fn written(&mut self, path: PathBuf, created: bool) {
+        if is_scratch(&path) {
+            return;
+        }
+
+        let moved_aside = self.queue.iter_mut().find(|queued| match queued.event {
+            FileEvent::Rename(ref from, _) => queued.until.is_some() && from == &path,
+            _ => false,
+        });
+        if let Some(queued) = moved_aside {
+            // Saved: the file never really went anywhere
+            queued.event = FileEvent::Write(path.clone());
+            queued.until = None;
+            self.known.insert(path);
+            return;
+        }
+
+        let event = if created && !self.known.contains(&path) {
+            FileEvent::Create(path.clone())
+        } else {
+            FileEvent::Write(path.clone())
+        };
+        self.known.insert(path);
+        self.enqueue(event, None);
+    }
+
+    fn enqueue(&mut self, event: FileEvent, until: Option<Instant>) {
+        self.queue.push_back(Queued {
+            event: event,
+            until: until,
+        });
+    }
+
+    /// The changes that are no longer waiting on anything, in the order they
+    /// happened
+    pub(super) fn expired(&mut self, now: Instant) -> Vec<FileEvent> {
+        let mut ready = Vec::new();
+        while self.queue.front().map_or(false, |q| q.until.map_or(true, |until| until <= now)) {
+            ready.push(self.queue.pop_front().unwrap().event);
+        }
+        ready
+    }
+
+    /// Everything still being held back, for when the daemon stops
+    pub(super) fn flush(&mut self) -> Vec<FileEvent> {
+        self.queue.drain(..).map(|queued| queued.event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    use notify::DebouncedEvent;
+
+    use squirrel::event::FileEvent;
+    use squirrel::event::FileEvent::*;
+    use squirrel::server::to_squirrel_event;
+
+    fn p(path: &str) -> PathBuf {
+        PathBuf::from(path)
+    }
+
+    /// Reads a line of a trace, as printed with `{:?}` by a program watching
+    /// `/tmp/cap` with notify's debounced watcher (with a 1s delay), after
+    /// how many milliseconds it arrived
+    fn parse(line: &str) -> (u64, DebouncedEvent) {
+        let line = line.trim();
+        let ms_end = line.find("ms ").unwrap();
+        let ms = line[..ms_end].trim().parse().unwrap();
+        let rest = &line[ms_end + 3..];
+        let open = rest.find('(').unwrap();
+        let paths: Vec<PathBuf> = rest[open + 1..rest.len() - 1]
+            .split(", ")
+            .map(|path| PathBuf::from(path.trim_matches('"')))
+            .collect();
+        let event = match &rest[..open] {
+            "NoticeWrite" => DebouncedEvent::NoticeWrite(paths[0].clone()),
+            "NoticeRemove" => DebouncedEvent::NoticeRemove(paths[0].clone()),
+            "Create" => DebouncedEvent::Create(paths[0].clone()),
+            "Write" => DebouncedEvent::Write(paths[0].clone()),
+            "Remove" => DebouncedEvent::Remove(paths[0].clone()),
+            "Rename" => DebouncedEvent::Rename(paths[0].clone(), paths[1].clone()),
+            other => panic!("unexpected event in trace: {}", other),
+        };
+        (ms, event)
+    }
+
+    /// Feeds a trace through as the daemon would, at the times it was
+    /// captured, then waits for anything held back
+    fn replay(trace: &str, known: &[&str]) -> Vec<FileEvent> {
+        let mut coalescer = super::new(known.iter().map(|k| p(k)).collect());
+        let start = Instant::now();
+        let mut recorded = Vec::new();
+        for line in trace.lines().filter(|l| !l.trim().is_empty()) {
+            let (ms, event) = parse(line);
+            let event = to_squirrel_event(event, Path::new("/tmp/cap")).unwrap();
+            recorded.extend(coalescer.push(event, start + Duration::from_millis(ms)));
+        }
+        recorded.extend(coalescer.expired(start + Duration::from_secs(60)));
+        recorded
+    }
+
+    // The traces below were captured from notify while each editor's save
+    // was carried out on `src/main.rs`: vim 9.0 itself (it writes in place,
+    // even with `backupcopy=no`), and the file operations the others make.
+
+    /// `vim -u NONE -N -es -c 'normal Goprintln' -c 'sleep 2' -c wq main.rs`
+    const VIM: &str = r#"
+         2503ms Create("/tmp/cap/src/.main.rs.swp")
+         3503ms NoticeWrite("/tmp/cap/src/.main.rs.swp")
+         3503ms NoticeWrite("/tmp/cap/src/main.rs")
+         3504ms NoticeRemove("/tmp/cap/src/.main.rs.swp")
+         4504ms Write("/tmp/cap/src/main.rs")
+         4504ms Remove("/tmp/cap/src/.main.rs.swp")
+    "#;
+
+    /// VS Code writes over the file in place
+    const VSCODE: &str = r#"
+         1501ms NoticeWrite("/tmp/cap/src/main.rs")
+         2501ms Write("/tmp/cap/src/main.rs")
+    "#;
+
+    /// IntelliJ's "safe write": the new version is written to
+    /// `main.rs___jb_tmp___`, the original renamed to `main.rs___jb_old___`,
+    /// the new version renamed into place and the original removed
+    const JETBRAINS: &str = r#"
+         1502ms NoticeRemove("/tmp/cap/src/main.rs")
+         2503ms Create("/tmp/cap/src/main.rs")
+    "#;
+
+    /// The same, for a file big enough that the steps straddle the
+    /// debouncing window
+    const JETBRAINS_SLOW: &str = r#"
+         2502ms Create("/tmp/cap/src/main.rs___jb_tmp___")
+         2705ms NoticeRemove("/tmp/cap/src/main.rs")
+         2706ms NoticeRemove("/tmp/cap/src/main.rs___jb_tmp___")
+         3705ms Rename("/tmp/cap/src/main.rs", "/tmp/cap/src/main.rs___jb_old___")
+         3706ms Rename("/tmp/cap/src/main.rs___jb_tmp___", "/tmp/cap/src/main.rs")
+         3908ms NoticeRemove("/tmp/cap/src/main.rs___jb_old___")
+         4908ms Remove("/tmp/cap/src/main.rs___jb_old___")
+    "#;
+
+    /// Emacs: a `.#main.rs` lock symlink while the buffer is modified, then
+    /// on the first save the original is renamed to `main.rs~` and the new
+    /// version written in its place
+    const EMACS: &str = r#"
+         1503ms NoticeRemove("/tmp/cap/src/main.rs")
+         2503ms Rename("/tmp/cap/src/main.rs", "/tmp/cap/src/main.rs~")
+         2504ms Create("/tmp/cap/src/main.rs")
+    "#;
+
+    /// gedit, through GIO: the new version is written to
+    /// `.goutputstream-XXXXXX` and renamed over the original
+    const GEDIT: &str = r#"
+         2502ms Create("/tmp/cap/src/main.rs")
+    "#;
+
+    /// Not an editor: `gen/` removed, then made again with something else in
+    const DELETE_AND_RECREATE: &str = r#"
+         2503ms Create("/tmp/cap/src/gen")
+         2503ms Create("/tmp/cap/src/gen/out.rs")
+         2706ms NoticeRemove("/tmp/cap/src/gen/out.rs")
+         2706ms NoticeRemove("/tmp/cap/src/gen")
+         3706ms Remove("/tmp/cap/src/gen/out.rs")
+         3706ms Remove("/tmp/cap/src/gen")
+         4909ms Create("/tmp/cap/src/gen")
+         4909ms Create("/tmp/cap/src/gen/new.rs")
+    "#;
+
+    #[test]
+    fn vim_saves_are_one_write() {
+        assert_eq!(replay(VIM, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn vscode_saves_are_one_write() {
+        assert_eq!(replay(VSCODE, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn jetbrains_safe_writes_are_one_write() {
+        assert_eq!(replay(JETBRAINS, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+        assert_eq!(replay(JETBRAINS_SLOW, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn emacs_saves_are_one_write() {
+        assert_eq!(replay(EMACS, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn gedit_saves_are_one_write() {
+        assert_eq!(replay(GEDIT, &["src/main.rs"]), vec![Write(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn new_files_are_still_created() {
+        assert_eq!(replay(GEDIT, &[]), vec![Create(p("src/main.rs"))]);
+    }
+
+    #[test]
+    fn changes_are_passed_on_in_order() {
+        assert_eq!(
+            replay(DELETE_AND_RECREATE, &[]),
+            vec![
+                Create(p("src/gen")),
+                Create(p("src/gen/out.rs")),
+                Remove(p("src/gen/out.rs")),
+                Remove(p("src/gen")),
+                Create(p("src/gen")),
+                Create(p("src/gen/new.rs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_wait_behind_a_file_that_has_been_moved_aside() {
+        let mut coalescer = super::new(vec![p("main.rs"), p("gen")]);
+        let start = Instant::now();
+
+        assert_eq!(coalescer.push(Rename(p("main.rs"), p("main.rs~")), start), vec![]);
+        assert_eq!(coalescer.push(Remove(p("gen")), start), vec![]);
+        assert_eq!(coalescer.push(Create(p("gen")), start), vec![]);
+        assert_eq!(
+            coalescer.expired(start + Duration::from_secs(2)),
+            vec![
+                Rename(p("main.rs"), p("main.rs~")),
+                Remove(p("gen")),
+                Create(p("gen")),
+            ]
+        );
+    }
+}
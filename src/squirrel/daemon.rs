@@ -0,0 +1,400 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::Duration;
+use libc;
+
+use config::Settings;
+use errors::*;
+
+use super::event::{get_timestamp_now, EventTime};
+use super::journal::{JournalReader, sqlite_journal, PagedJournalQuery};
+use super::server::{ensure_dir, run_squirrel};
+
+const PID_FILE: &str = "daemon.pid";
+const LOG_FILE: &str = "daemon.log";
+
+/// How long to wait for a daemon to start up or shut down before giving up
+/// on it
+const STARTUP_WAIT_MS: u64 = 2000;
+const SHUTDOWN_WAIT_MS: u64 = 30000;
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// The daemon that holds the pidfile lock for a stash
+#[derive(Debug, PartialEq)]
+pub(crate) struct DaemonInfo {
+    pub pid: u32,
+    pub started: EventTime,
+    /// Whether it removes old snapshots on a schedule
+    pub collects_garbage: bool,
+}
+
+/// Held by the running daemon for as long as it runs. The file itself is
+/// left behind when the daemon stops: it's the lock on it that says whether a
+/// daemon is running, and that goes away with the process. Removing the file
+/// could instead remove the one a newly started daemon has just locked.
+pub(crate) struct PidFile {
+    _file: File,
+}
+
+/// Tries to take the lock without waiting; `Ok(false)` means somebody else
+/// has it
+fn try_lock(file: &File, operation: libc::c_int) -> Result<bool> {
+    let result = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(e.into()),
+    }
+}
+
+fn read_info(file: &mut File) -> Result<DaemonInfo> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|l| l.parse().ok());
+    let started = lines.next().and_then(|l| EventTime::parse(l).ok());
+    let collects_garbage = lines.next() == Some("gc");
+    match (pid, started) {
+        (Some(pid), Some(started)) => Ok(DaemonInfo {
+            pid: pid,
+            started: started,
+            collects_garbage: collects_garbage,
+        }),
+        _ => Err(format!("unable to read the daemon's pidfile: {:?}", contents).into()),
+    }
+}
+
+/// Reads the pidfile of a daemon known to hold the lock. One that has only
+/// just taken it may not have finished writing the file yet, so give it a
+/// moment before deciding the file can't be read.
+fn read_locked_info(file: &mut File) -> Result<DaemonInfo> {
+    let started = Instant::now();
+    loop {
+        match read_info(file) {
+            Ok(info) => return Ok(info),
+            Err(e) => if started.elapsed() >= StdDuration::from_millis(STARTUP_WAIT_MS) {
+                return Err(e);
+            },
+        }
+        thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+/// Marks this process as the daemon for the stash, failing if another one is
+/// already running
+pub(crate) fn acquire(stash_path: &Path, collects_garbage: bool) -> Result<PidFile> {
+    let path = stash_path.join(PID_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)?;
+
+    if !try_lock(&file, libc::LOCK_EX)? {
+        let info = read_locked_info(&mut file)?;
+        return Err(ErrorKind::AlreadyRunning(info.pid).into());
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}\n{}\n", process::id(), get_timestamp_now().rfc3339())?;
+    if collects_garbage {
+        write!(file, "gc\n")?;
+    }
+    file.sync_all()?;
+
+    Ok(PidFile { _file: file })
+}
+
+/// Finds the daemon watching the stash, if there is one
+pub(crate) fn running(stash_path: &Path) -> Result<Option<DaemonInfo>> {
+    let mut file = match File::open(stash_path.join(PID_FILE)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if try_lock(&file, libc::LOCK_SH)? {
+        // Left behind by a daemon that has stopped
+        return Ok(None);
+    }
+
+    read_locked_info(&mut file).map(Some)
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_: libc::c_int) {
+    if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+        // Asked twice; stop waiting for the shutdown to finish
+        unsafe { libc::_exit(1) };
+    }
+}
+
+fn handle_stop_signals() {
+    let handler = on_stop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Whether the daemon has been asked to stop, by `squirrel stop` or Ctrl-C
+pub(crate) fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Forks; the child carries on in the background, in a session of its own,
+/// with its output going to the log file. Returns the child's pid in the
+/// parent, and `None` in the child.
+fn detach(log_path: &Path) -> Result<Option<u32>> {
+    let log = OpenOptions::new().create(true).append(true).open(log_path)?;
+    let dev_null = File::open("/dev/null")?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            unsafe {
+                libc::setsid();
+                libc::dup2(dev_null.as_raw_fd(), 0);
+                libc::dup2(log.as_raw_fd(), 1);
+                libc::dup2(log.as_raw_fd(), 2);
+            }
+            Ok(None)
+        }
+        pid => Ok(Some(pid as u32)),
+    }
+}
+
+/// Waits for the daemon with the given pid to take the lock on the stash.
+/// Only reads the pidfile, so as not to get in the way of the lock itself.
+fn wait_for_start(stash_path: &Path, pid: u32) -> Result<bool> {
+    let started = Instant::now();
+    while started.elapsed() < StdDuration::from_millis(STARTUP_WAIT_MS) {
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } != 0 {
+            return Ok(false);
+        }
+        if let Ok(mut file) = File::open(stash_path.join(PID_FILE)) {
+            if read_info(&mut file).map(|info| info.pid == pid).unwrap_or(false) {
+                return Ok(true);
+            }
+        }
+        thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+    }
+    Ok(false)
+}
+
+pub(crate) fn run_daemon(watched_dir: &Path, settings: &Settings, foreground: bool) -> Result<()> {
+    let stash_path = &settings.stash_path;
+    ensure_dir(&stash_path)?;
+
+    if let Some(info) = running(&stash_path)? {
+        return Err(ErrorKind::AlreadyRunning(info.pid).into());
+    }
+
+    if !foreground {
+        let log_path = stash_path.join(LOG_FILE);
+        if let Some(pid) = detach(&log_path)? {
+            if !wait_for_start(&stash_path, pid)? {
+                return Err(ErrorKind::DaemonDidNotStart(log_path.to_string_lossy().into_owned()).into());
+            }
+            println!(
+                "squirrel is watching {} (pid {}); its log is in {}",
+                watched_dir.to_string_lossy(),
+                pid,
+                log_path.to_string_lossy()
+            );
+            return Ok(());
+        }
+    }
+
+    let _pid_file = acquire(&stash_path, settings.gc_interval.is_some())?;
+    handle_stop_signals();
+    info!("Watching {} as pid {}", watched_dir.to_string_lossy(), process::id());
+
+    run_squirrel(watched_dir, settings)
+}
+
+fn describe_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, seconds % 60)
+    }
+}
+
+pub(crate) fn show_status(stash_path: &Path) -> Result<()> {
+    let daemon = running(&stash_path)?;
+    match daemon {
+        Some(ref info) => println!(
+            "running (pid {}), up {} since {}",
+            info.pid,
+            describe_duration(get_timestamp_now().duration_since(&info.started)),
+            info.started
+        ),
+        None => println!("not running"),
+    }
+
+    if !stash_path.join("event-log.db").exists() {
+        return Ok(());
+    }
+    let journal = sqlite_journal::new(&stash_path)?;
+    let mut back = journal.backwards()?;
+    let mut events = back.next_page()?.peekable();
+
+    let last_event = match events.peek() {
+        Some(&Ok(ref event)) => Some(event.timestamp.clone()),
+        _ => None,
+    };
+    if let Some(ref info) = daemon {
+        let mut recorded = 0;
+        for event in events {
+            if event?.timestamp < info.started {
+                break;
+            }
+            recorded += 1;
+        }
+        println!("events recorded since then: {}", recorded);
+    }
+    match last_event {
+        Some(timestamp) => println!("last event: {}", timestamp),
+        None => println!("last event: none yet"),
+    }
+
+    let failures = journal.failures()?;
+    if let Some(ref info) = daemon {
+        let since = failures.iter().filter(|f| f.timestamp >= info.started).count();
+        println!("failures recorded since then: {}", since);
+    }
+    if let Some(failure) = failures.last() {
+        let path = failure
+            .path
+            .as_ref()
+            .map(|p| format!("{}: ", p.to_string_lossy()))
+            .unwrap_or_default();
+        println!(
+            "last failure ({} in all): {} {}{}",
+            failures.len(),
+            failure.timestamp,
+            path,
+            failure.detail
+        );
+    }
+
+    Ok(())
+}
+
+/// Asks the daemon to stop, and waits for it to finish recording whatever it
+/// was in the middle of
+pub(crate) fn stop_daemon(stash_path: &Path) -> Result<()> {
+    let info = match running(&stash_path)? {
+        Some(info) => info,
+        None => return Err(ErrorKind::NotRunning.into()),
+    };
+
+    if unsafe { libc::kill(info.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let asked = Instant::now();
+    while asked.elapsed() < StdDuration::from_millis(SHUTDOWN_WAIT_MS) {
+        if running(&stash_path)?.as_ref() != Some(&info) {
+            println!("stopped");
+            return Ok(());
+        }
+        thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+    }
+
+    println!("asked the daemon (pid {}) to stop, but it's still shutting down", info.pid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use errors::*;
+
+    #[test]
+    fn only_one_daemon_can_hold_the_stash() {
+        let stash = TempDir::new("squirrel-daemon").unwrap();
+
+        let pid_file = super::acquire(stash.path(), false).unwrap();
+        match super::acquire(stash.path(), false) {
+            Err(Error(ErrorKind::AlreadyRunning(pid), _)) => assert_eq!(pid, process::id()),
+            _ => panic!("a second daemon was allowed to start"),
+        }
+
+        drop(pid_file);
+        assert!(super::acquire(stash.path(), false).is_ok());
+    }
+
+    #[test]
+    fn the_running_daemon_can_be_found() {
+        let stash = TempDir::new("squirrel-daemon").unwrap();
+        assert_eq!(super::running(stash.path()).unwrap(), None);
+
+        let pid_file = super::acquire(stash.path(), false).unwrap();
+        let info = super::running(stash.path()).unwrap().unwrap();
+        assert_eq!(info.pid, process::id());
+
+        drop(pid_file);
+        assert!(stash.path().join(super::PID_FILE).exists());
+        assert_eq!(super::running(stash.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_pidfile_still_being_written_is_waited_for() {
+        let stash = TempDir::new("squirrel-daemon").unwrap();
+        let path = stash.path().join(super::PID_FILE);
+        let _pid_file = super::acquire(stash.path(), true).unwrap();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            File::create(&path)
+                .unwrap()
+                .write_all(b"1\n2017-10-21T20:34:00+00:00\ngc\n")
+                .unwrap();
+        });
+
+        let info = super::running(stash.path()).unwrap().unwrap();
+        assert_eq!(info.pid, 1);
+        assert!(info.collects_garbage);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn stale_pidfiles_are_ignored() {
+        let stash = TempDir::new("squirrel-daemon").unwrap();
+        File::create(stash.path().join(super::PID_FILE))
+            .unwrap()
+            .write_all(b"1\n2017-10-21T20:34:00+00:00\n")
+            .unwrap();
+
+        assert_eq!(super::running(stash.path()).unwrap(), None);
+        assert!(super::acquire(stash.path(), false).is_ok());
+    }
+}
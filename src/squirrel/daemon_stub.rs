@@ -0,0 +1,46 @@
+// Stands in for the daemon on platforms without `flock`, `fork` and signals.
+// squirrel can still watch a directory in the foreground, but nothing keeps
+// track of whether it's doing so.
+
+use std::path::Path;
+
+use config::Settings;
+use errors::*;
+
+use super::event::EventTime;
+use super::server::{ensure_dir, run_squirrel};
+
+/// The daemon that holds the pidfile lock for a stash
+#[derive(Debug, PartialEq)]
+pub(crate) struct DaemonInfo {
+    pub pid: u32,
+    pub started: EventTime,
+    /// Whether it removes old snapshots on a schedule
+    pub collects_garbage: bool,
+}
+
+/// Finds the daemon watching the stash, which can't be done here
+pub(crate) fn running(_stash_path: &Path) -> Result<Option<DaemonInfo>> {
+    Ok(None)
+}
+
+/// Without signals, the daemon is only ever stopped by Ctrl-C killing it
+pub(crate) fn stop_requested() -> bool {
+    false
+}
+
+pub(crate) fn run_daemon(watched_dir: &Path, settings: &Settings, foreground: bool) -> Result<()> {
+    if !foreground {
+        return Err("the daemon can only run in the foreground on this platform; pass --foreground".into());
+    }
+    ensure_dir(&settings.stash_path)?;
+    run_squirrel(watched_dir, settings)
+}
+
+pub(crate) fn show_status(_stash_path: &Path) -> Result<()> {
+    Err("status isn't supported on this platform".into())
+}
+
+pub(crate) fn stop_daemon(_stash_path: &Path) -> Result<()> {
+    Err("stop isn't supported on this platform".into())
+}
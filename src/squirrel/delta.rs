@@ -0,0 +1,182 @@
+use std::str;
+
+use diff;
+
+use errors::*;
+
+/// Beyond this many (base lines x new lines) working out a delta costs more
+/// than it is likely to save, so the contents are stored whole instead
+pub(super) const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Splits contents into lines, each keeping its `\n`. Binary contents are
+/// split the same way; they just tend to have fewer, longer "lines".
+fn split_lines(contents: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, b) in contents.iter().enumerate() {
+        if *b == b'\n' {
+            lines.push(&contents[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < contents.len() {
+        lines.push(&contents[start..]);
+    }
+    lines
+}
+
+/// Describes `target` as lines copied from `base` interleaved with inserted
+/// bytes:
+///
+///     c <first line> <line count>
+///     i <byte count>
+///     <bytes>
+///
+/// Returns `None` if the files are too big to compare cheaply.
+pub(crate) fn compute(base: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    let base_lines = split_lines(base);
+    let target_lines = split_lines(target);
+    if base_lines.len().saturating_mul(target_lines.len()) > MAX_DIFF_CELLS {
+        return None;
+    }
+
+    let mut delta = Vec::new();
+    let mut base_line = 0;
+    let mut copy: Option<(usize, usize)> = None;
+    let mut insert: Vec<u8> = Vec::new();
+
+    for change in diff::slice(&base_lines, &target_lines) {
+        match change {
+            diff::Result::Both(..) => {
+                flush_insert(&mut delta, &mut insert);
+                copy = match copy {
+                    Some((start, count)) => Some((start, count + 1)),
+                    None => Some((base_line, 1)),
+                };
+                base_line += 1;
+            }
+            diff::Result::Left(..) => {
+                flush_copy(&mut delta, &mut copy);
+                base_line += 1;
+            }
+            diff::Result::Right(line) => {
+                flush_copy(&mut delta, &mut copy);
+                insert.extend_from_slice(line);
+            }
+        }
+    }
+    flush_copy(&mut delta, &mut copy);
+    flush_insert(&mut delta, &mut insert);
+
+    Some(delta)
+}
+
+fn flush_copy(delta: &mut Vec<u8>, copy: &mut Option<(usize, usize)>) {
+    if let Some((start, count)) = copy.take() {
+        delta.extend_from_slice(format!("c {} {}\n", start, count).as_bytes());
+    }
+}
+
+fn flush_insert(delta: &mut Vec<u8>, insert: &mut Vec<u8>) {
+    if !insert.is_empty() {
+        delta.extend_from_slice(format!("i {}\n", insert.len()).as_bytes());
+        delta.append(insert);
+    }
+}
+
+fn corrupt(detail: &str) -> Error {
+    ErrorKind::CorruptSnapshot(detail.to_owned()).into()
+}
+
+/// Splits the next `\n`-terminated line of text off the front of `input`
+pub(crate) fn take_line<'a>(input: &mut &'a [u8]) -> Result<&'a str> {
+    let end = input
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| corrupt("delta is truncated"))?;
+    let line = str::from_utf8(&input[..end]).map_err(|_| corrupt("delta is not readable"))?;
+    *input = &input[end + 1..];
+    Ok(line)
+}
+
+fn number(word: Option<&str>) -> Result<usize> {
+    word.and_then(|w| w.parse().ok())
+        .ok_or_else(|| corrupt("delta has an unreadable instruction"))
+}
+
+/// Rebuilds the contents that `delta` was computed from, given its base
+pub(crate) fn apply(base: &[u8], mut delta: &[u8]) -> Result<Vec<u8>> {
+    let base_lines = split_lines(base);
+    let mut contents = Vec::new();
+
+    while !delta.is_empty() {
+        let instruction = take_line(&mut delta)?;
+        let mut words = instruction.split(' ');
+        match words.next() {
+            Some("c") => {
+                let start = number(words.next())?;
+                let count = number(words.next())?;
+                let lines = base_lines
+                    .get(start..start + count)
+                    .ok_or_else(|| corrupt("delta copies past the end of its base"))?;
+                for line in lines {
+                    contents.extend_from_slice(line);
+                }
+            }
+            Some("i") => {
+                let length = number(words.next())?;
+                if length > delta.len() {
+                    return Err(corrupt("delta is truncated"));
+                }
+                let (inserted, rest) = delta.split_at(length);
+                contents.extend_from_slice(inserted);
+                delta = rest;
+            }
+            _ => return Err(corrupt("delta has an unreadable instruction")),
+        }
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    fn round_trip(base: &[u8], target: &[u8]) {
+        let delta = super::compute(base, target).unwrap();
+        assert_eq!(super::apply(base, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn text_changes_round_trip() {
+        let base = b"one\ntwo\nthree\nfour\n";
+        round_trip(base, b"one\ntwo\nthree\nfour\n");
+        round_trip(base, b"zero\none\ntwo\nthree\nfour\n");
+        round_trip(base, b"one\nthree\nfour\nfive");
+        round_trip(base, b"");
+        round_trip(b"", base);
+    }
+
+    #[test]
+    fn binary_changes_round_trip() {
+        let base: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let mut target = base.clone();
+        target[100] = 0;
+        target.extend_from_slice(&[0, 1, 2]);
+        round_trip(&base, &target);
+    }
+
+    #[test]
+    fn small_edits_make_small_deltas() {
+        let base: String = (0..1000).map(|n| format!("line {}\n", n)).collect();
+        let target = base.replace("line 500\n", "line five hundred\n");
+        let delta = super::compute(base.as_bytes(), target.as_bytes()).unwrap();
+        assert!(delta.len() < 64);
+    }
+
+    #[test]
+    fn damaged_deltas_are_refused() {
+        assert!(super::apply(b"one\n", b"c 0 2\n").is_err());
+        assert!(super::apply(b"one\n", b"i 10\nshort").is_err());
+        assert!(super::apply(b"one\n", b"x\n").is_err());
+    }
+}
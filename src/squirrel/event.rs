@@ -1,13 +1,15 @@
 
+use std::fs;
 use std::result::Result as StdResult;
 use std::path::{PathBuf, Path};
 use std::fmt::{Display, Error as FmtError, Formatter, Result as FmtResult};
 
+use chrono::Duration;
 use chrono::prelude::{DateTime, Utc, TimeZone};
 
 use errors::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum FileEvent {
     Write(PathBuf),
     Create(PathBuf),
@@ -30,14 +32,35 @@ impl FileEvent {
 
 pub(crate) type EventId = i64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct EventTime(DateTime<Utc>);
 
 impl EventTime {
+    /// Parses a point in time given on the command line. Accepts the format
+    /// used when displaying events (`2017-10-21 20:34`, in UTC), or a full
+    /// RFC 3339 timestamp.
+    pub(crate) fn parse(s: &str) -> Result<EventTime> {
+        if let Ok(t) = Utc.datetime_from_str(s, "%Y-%m-%d %H:%M") {
+            return Ok(EventTime(t));
+        }
+
+        DateTime::parse_from_rfc3339(s)
+            .map(EventTime::from_date_time)
+            .map_err(|_| ErrorKind::InvalidTimestamp(s.to_owned()).into())
+    }
+
     pub(crate) fn rfc3339(&self) -> String {
         self.0.to_rfc3339()
     }
 
+    pub(crate) fn duration_since(&self, earlier: &EventTime) -> Duration {
+        self.0.signed_duration_since(earlier.0)
+    }
+
+    pub(crate) fn format(&self, fmt: &str) -> String {
+        format!("{}", self.0.format(fmt))
+    }
+
     pub(crate) fn from_date_time<Tz>(from: DateTime<Tz>) -> EventTime
     where
         Tz: TimeZone,
@@ -57,12 +80,17 @@ pub(crate) fn get_timestamp_now() -> EventTime {
     EventTime(Utc::now())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum EventType {
     Create,
     Remove,
     Update,
     Rename,
+    Restore,
+    /// The state of a file when the daemon started watching it
+    Baseline,
+    /// A file written without its contents changing
+    Touch,
 }
 
 impl EventType {
@@ -75,6 +103,12 @@ impl EventType {
             Ok(EventType::Update)
         } else if s == "Rename" {
             Ok(EventType::Rename)
+        } else if s == "Restore" {
+            Ok(EventType::Restore)
+        } else if s == "Baseline" {
+            Ok(EventType::Baseline)
+        } else if s == "Touch" {
+            Ok(EventType::Touch)
         } else {
             Err(format!("unable to convert '{}' to EventType", s).into())
         }
@@ -88,24 +122,154 @@ impl Display for EventType {
             &EventType::Remove => write!(f, "Remove"),
             &EventType::Update => write!(f, "Update"),
             &EventType::Rename => write!(f, "Rename"),
+            &EventType::Restore => write!(f, "Restore"),
+            &EventType::Baseline => write!(f, "Baseline"),
+            &EventType::Touch => write!(f, "Touch"),
+        }
+    }
+}
+
+/// How the contents of a snapshot are encoded on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Codec {
+    None,
+    Gzip,
+}
+
+impl Codec {
+    pub(crate) fn from_str(s: &str) -> Result<Codec> {
+        if s == "none" {
+            Ok(Codec::None)
+        } else if s == "gzip" {
+            Ok(Codec::Gzip)
+        } else {
+            Err(ErrorKind::UnknownCodec(s.to_owned()).into())
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Codec::None => write!(f, "none"),
+            &Codec::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Snapshot {
+    /// File contents kept in the object store, keyed by their hash
+    Object(String, Codec),
+    /// A whole-file copy taken by older versions of squirrel, named by a
+    /// random prefix and kept directly in the stash
+    Legacy(PathBuf),
+}
+
+impl Snapshot {
+    pub(crate) fn from_str(s: &str, codec: Codec) -> Snapshot {
+        let is_hash = s.len() == 40 &&
+            s.chars().all(|c| c.is_digit(16) && !c.is_uppercase());
+        if is_hash {
+            Snapshot::Object(s.to_owned(), codec)
+        } else {
+            Snapshot::Legacy(PathBuf::from(s))
+        }
+    }
+
+    pub(crate) fn codec(&self) -> Codec {
+        match self {
+            &Snapshot::Object(_, codec) => codec,
+            &Snapshot::Legacy(_) => Codec::None,
+        }
+    }
+
+    /// The hash of the contents, for snapshots named after theirs
+    pub(crate) fn content_hash(&self) -> Option<&str> {
+        match self {
+            &Snapshot::Object(ref hash, _) => Some(hash),
+            &Snapshot::Legacy(_) => None,
+        }
+    }
+}
+
+/// Displays the name of the snapshot, as it's recorded in the journal
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Snapshot::Object(ref hash, _) => write!(f, "{}", hash),
+            &Snapshot::Legacy(ref path) => write!(f, "{}", path.to_string_lossy()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Event {
     pub event_id: Option<EventId>,
     pub event_type: EventType,
     pub timestamp: EventTime,
-    pub snapshot: Option<PathBuf>,
+    pub snapshot: Option<Snapshot>,
     pub before_path: Option<PathBuf>,
     pub after_path: Option<PathBuf>,
+    /// Set once the snapshot has been removed by garbage collection. The
+    /// event itself is kept, but no longer has a `snapshot`.
+    pub pruned: bool,
+    /// Set for changes found when the daemon started up, which happened
+    /// while it wasn't running, rather than seen as they happened
+    pub reconciled: bool,
+    /// The file as it was when the snapshot was taken
+    pub stat: Option<FileStat>,
+    /// The hash of the snapshot's contents, which is kept even once the
+    /// snapshot itself has been pruned
+    pub hash: Option<String>,
+}
+
+/// The size and modification time of a file, which are enough to tell
+/// cheaply whether it has changed since it was last snapshotted, and its
+/// permissions, so that a restored file gets them back
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FileStat {
+    pub size: u64,
+    pub mtime: EventTime,
+    /// Unix permission bits, where there are such things
+    pub mode: Option<u32>,
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+pub(crate) fn file_stat(path: &Path) -> Option<FileStat> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?;
+    Some(FileStat {
+        size: metadata.len(),
+        mtime: EventTime::from_date_time(DateTime::<Utc>::from(mtime)),
+        mode: mode_of(&metadata),
+    })
+}
+
+impl Event {
+    /// The path of the file as it was once this event had happened.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.after_path
+            .as_ref()
+            .or(self.before_path.as_ref())
+            .map(|p| p.as_path())
+    }
 }
 
 pub(crate) fn new_event(
     event_type: EventType,
     timestamp: EventTime,
-    snapshot: Option<PathBuf>,
+    snapshot: Option<Snapshot>,
     after_path: Option<PathBuf>,
     before_path: Option<PathBuf>,
 ) -> Event {
@@ -113,8 +277,12 @@ pub(crate) fn new_event(
         event_id: None,
         event_type: event_type,
         timestamp: timestamp,
-        snapshot: snapshot,
         before_path: before_path,
         after_path: after_path,
+        pruned: false,
+        reconciled: false,
+        stat: None,
+        hash: snapshot.as_ref().and_then(|s| s.content_hash()).map(|h| h.to_owned()),
+        snapshot: snapshot,
     }
 }
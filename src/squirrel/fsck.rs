@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::daemon;
+use super::event::{EventId, Snapshot};
+use super::gc::all_events;
+use super::journal::Journal;
+use super::journal::sqlite_journal::{self, SqliteJournal};
+use super::snapshot_store::{self, content_hash, SnapshotStore, StoredFile};
+
+use errors::*;
+
+/// Where `--repair` moves damaged snapshots, in case anything can be saved
+/// from them by hand
+const QUARANTINE: &str = "quarantine";
+
+/// Something wrong with the stash
+#[derive(Debug, PartialEq)]
+pub(crate) enum Problem {
+    /// Sqlite found the event log itself to be damaged
+    DamagedJournal(String),
+    /// Events which refer to a snapshot that isn't there
+    MissingSnapshot(Snapshot, Vec<EventId>),
+    /// A snapshot which can't be read back, or whose contents don't match
+    /// their hash, along with the events that refer to it
+    DamagedSnapshot(PathBuf, String, Vec<EventId>),
+    /// Left behind by a snapshot which was still being written when squirrel
+    /// stopped
+    LeftoverTemporaryFile(PathBuf),
+    /// A snapshot no event refers to, e.g. because squirrel stopped between
+    /// writing it and recording the event
+    OrphanedSnapshot(PathBuf),
+}
+
+fn list(event_ids: &[EventId]) -> String {
+    let ids: Vec<String> = event_ids.iter().map(|id| id.to_string()).collect();
+    ids.join(", ")
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Problem::DamagedJournal(ref detail) => write!(f, "the event log is damaged: {}", detail),
+            &Problem::MissingSnapshot(ref snapshot, ref event_ids) => {
+                write!(f, "snapshot {} is missing (used by events {})", snapshot, list(event_ids))
+            }
+            &Problem::DamagedSnapshot(ref path, ref detail, ref event_ids) => write!(
+                f,
+                "damaged snapshot: {} (used by events {}): {}",
+                path.to_string_lossy(),
+                list(event_ids),
+                detail
+            ),
+            &Problem::LeftoverTemporaryFile(ref path) => {
+                write!(f, "leftover temporary file: {}", path.to_string_lossy())
+            }
+            &Problem::OrphanedSnapshot(ref path) => {
+                write!(f, "snapshot no event refers to: {}", path.to_string_lossy())
+            }
+        }
+    }
+}
+
+impl Problem {
+    /// Puts the problem right, as far as that's possible. Events which have
+    /// lost their snapshot are marked as pruned, as if garbage collection
+    /// had removed it; damaged snapshots are moved out of the way into the
+    /// quarantine directory. Returns whether the problem could be repaired.
+    fn repair(&self, journal: &mut SqliteJournal, stash_path: &Path) -> Result<bool> {
+        match self {
+            &Problem::DamagedJournal(_) => return Ok(false),
+            &Problem::MissingSnapshot(_, ref event_ids) => journal.prune(event_ids)?,
+            &Problem::DamagedSnapshot(ref path, _, ref event_ids) => {
+                journal.prune(event_ids)?;
+                let quarantine = stash_path.join(QUARANTINE);
+                fs::create_dir_all(&quarantine)?;
+                let fan_out = path.parent().and_then(|p| p.file_name()).unwrap();
+                let name = format!(
+                    "{}{}",
+                    fan_out.to_string_lossy(),
+                    path.file_name().unwrap().to_string_lossy()
+                );
+                fs::rename(path, quarantine.join(name))?;
+            }
+            &Problem::LeftoverTemporaryFile(ref path) |
+            &Problem::OrphanedSnapshot(ref path) => fs::remove_file(path)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Reads a snapshot back, deltas and all, and checks it against its hash
+fn verify(store: &SnapshotStore, snapshot: &Snapshot) -> ::std::result::Result<(), String> {
+    let contents = store.read(snapshot).map_err(|e| e.to_string())?;
+    match snapshot {
+        &Snapshot::Object(ref hash, _) if content_hash(&contents) != *hash => {
+            Err(String::from("its contents don't match its hash"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Every snapshot needed to read back `snapshots`, including the ones that
+/// deltas are based on. Deltas which can't be read are left to `verify` to
+/// report.
+fn needed_snapshots<'a, I>(store: &SnapshotStore, snapshots: I) -> HashSet<Snapshot>
+where
+    I: Iterator<Item = &'a Snapshot>,
+{
+    let mut needed = HashSet::new();
+    let mut to_visit: Vec<Snapshot> = snapshots.cloned().collect();
+    while let Some(snapshot) = to_visit.pop() {
+        if let Ok(Some((base, _))) = store.delta_base(&snapshot) {
+            if !needed.contains(&base) {
+                to_visit.push(base);
+            }
+        }
+        needed.insert(snapshot);
+    }
+    needed
+}
+
+pub(crate) fn check(stash_path: &Path) -> Result<Vec<Problem>> {
+    let journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+
+    let mut problems: Vec<Problem> = journal
+        .integrity_check()?
+        .into_iter()
+        .map(Problem::DamagedJournal)
+        .collect();
+
+    // Oldest first, so that problems are reported in the order they arose
+    let mut events = all_events(&journal)?;
+    events.reverse();
+    let mut users: HashMap<Snapshot, Vec<EventId>> = HashMap::new();
+    let mut in_order = Vec::new();
+    for event in events {
+        if let Some(snapshot) = event.snapshot {
+            let event_ids = users.entry(snapshot.clone()).or_insert_with(Vec::new);
+            if event_ids.is_empty() {
+                in_order.push(snapshot);
+            }
+            event_ids.push(event.event_id.unwrap());
+        }
+    }
+
+    for snapshot in &in_order {
+        let event_ids = users[snapshot].clone();
+        if !store.contains(snapshot) {
+            problems.push(Problem::MissingSnapshot(snapshot.clone(), event_ids));
+        } else if let Err(detail) = verify(&store, snapshot) {
+            problems.push(Problem::DamagedSnapshot(store.path_of(snapshot), detail, event_ids));
+        }
+    }
+
+    let needed = needed_snapshots(&store, in_order.iter());
+    for stored in store.stored_files()? {
+        match stored {
+            StoredFile::Snapshot(ref snapshot, ref path) if !needed.contains(snapshot) => {
+                problems.push(Problem::OrphanedSnapshot(path.clone()))
+            }
+            StoredFile::Snapshot(..) => (),
+            StoredFile::Temporary(path) => problems.push(Problem::LeftoverTemporaryFile(path)),
+            StoredFile::Unrecognised(path) => {
+                warn!("Leaving alone {}, which squirrel didn't write", path.to_string_lossy())
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+pub fn run_fsck(stash_path: &Path, repair: bool) -> Result<()> {
+    if repair {
+        // The daemon may be halfway through writing a snapshot
+        if let Some(info) = daemon::running(&stash_path)? {
+            return Err(ErrorKind::DaemonStillRunning(info.pid).into());
+        }
+    }
+
+    let problems = check(&stash_path)?;
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+
+    if !repair {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        println!("Run `squirrel fsck --repair` to remove or quarantine the damaged pieces");
+        return Err(ErrorKind::DamagedStash(problems.len()).into());
+    }
+
+    let mut journal = sqlite_journal::new(&stash_path)?;
+    let mut unrepaired = 0;
+    for problem in &problems {
+        if problem.repair(&mut journal, &stash_path)? {
+            println!("repaired: {}", problem);
+        } else {
+            println!("can't repair: {}", problem);
+            unrepaired += 1;
+        }
+    }
+
+    if unrepaired > 0 {
+        Err(ErrorKind::DamagedStash(unrepaired).into())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use squirrel::event::*;
+    use squirrel::journal::{sqlite_journal, Journal, JournalReader, PagedJournalQuery};
+    use squirrel::snapshot_store::{self, SnapshotStore};
+    use super::Problem;
+
+    fn snapshot_of(store: &SnapshotStore, stash: &Path, contents: &[u8]) -> Snapshot {
+        let file = stash.join("file.txt");
+        File::create(&file).unwrap().write_all(contents).unwrap();
+        store.store(&file, None).unwrap()
+    }
+
+    fn record<J: Journal>(journal: &mut J, snapshot: &Snapshot) {
+        journal
+            .journal(new_event(
+                EventType::Update,
+                get_timestamp_now(),
+                Some(snapshot.clone()),
+                Some("file.txt".into()),
+                None,
+            ))
+            .unwrap();
+    }
+
+    fn repair_all(stash: &Path) {
+        let mut journal = sqlite_journal::new(stash).unwrap();
+        for problem in super::check(stash).unwrap() {
+            assert!(problem.repair(&mut journal, stash).unwrap());
+        }
+    }
+
+    #[test]
+    fn orphans_and_leftovers_are_found_and_removed() {
+        let stash = TempDir::new("squirrel-fsck").unwrap();
+        let store = snapshot_store::new(stash.path());
+        let mut journal = sqlite_journal::new(stash.path()).unwrap();
+
+        let kept = snapshot_of(&store, stash.path(), b"kept");
+        record(&mut journal, &kept);
+        let orphan = snapshot_of(&store, stash.path(), b"orphaned");
+        let leftover = store.path_of(&kept).with_file_name(".tmp-1-abc.gz");
+        File::create(&leftover).unwrap();
+
+        let problems = super::check(stash.path()).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(problems.contains(&Problem::OrphanedSnapshot(store.path_of(&orphan))));
+        assert!(problems.contains(&Problem::LeftoverTemporaryFile(leftover.clone())));
+
+        repair_all(stash.path());
+        assert!(super::check(stash.path()).unwrap().is_empty());
+        assert!(store.contains(&kept));
+        assert!(!fs::metadata(&leftover).is_ok());
+    }
+
+    #[test]
+    fn missing_and_damaged_snapshots_are_found_and_their_events_pruned() {
+        let stash = TempDir::new("squirrel-fsck").unwrap();
+        let store = snapshot_store::new(stash.path());
+        let mut journal = sqlite_journal::new(stash.path()).unwrap();
+
+        let missing = snapshot_of(&store, stash.path(), b"missing");
+        record(&mut journal, &missing);
+        let damaged = snapshot_of(&store, stash.path(), b"damaged");
+        record(&mut journal, &damaged);
+        let healthy = snapshot_of(&store, stash.path(), b"healthy");
+        record(&mut journal, &healthy);
+
+        fs::remove_file(store.path_of(&missing)).unwrap();
+        let damaged_path = store.path_of(&damaged);
+        File::create(&damaged_path).unwrap().write_all(b"not gzip").unwrap();
+
+        let problems = super::check(stash.path()).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0], Problem::MissingSnapshot(missing, vec![1]));
+        match problems[1] {
+            Problem::DamagedSnapshot(ref path, _, ref event_ids) => {
+                assert_eq!(path, &damaged_path);
+                assert_eq!(event_ids, &vec![2]);
+            }
+            ref other => panic!("unexpected problem: {}", other),
+        }
+
+        repair_all(stash.path());
+        assert!(super::check(stash.path()).unwrap().is_empty());
+        assert!(stash.path().join(super::QUARANTINE).read_dir().unwrap().count() == 1);
+
+        let mut back = journal.backwards().unwrap();
+        let pruned: Vec<bool> = back.next_page().unwrap().map(|e| e.unwrap().pruned).collect();
+        assert_eq!(pruned, vec![false, true, true]);
+    }
+
+    #[test]
+    fn contents_are_checked_against_their_hash() {
+        let stash = TempDir::new("squirrel-fsck").unwrap();
+        let mut store = snapshot_store::new(stash.path());
+        store.set_codec(Codec::None);
+        let mut journal = sqlite_journal::new(stash.path()).unwrap();
+
+        let snapshot = snapshot_of(&store, stash.path(), b"original");
+        record(&mut journal, &snapshot);
+        File::create(store.path_of(&snapshot)).unwrap().write_all(b"tampered").unwrap();
+
+        let problems = super::check(stash.path()).unwrap();
+        assert_eq!(problems.len(), 1);
+        match problems[0] {
+            Problem::DamagedSnapshot(_, ref detail, _) => assert!(detail.contains("hash")),
+            ref other => panic!("unexpected problem: {}", other),
+        }
+    }
+}
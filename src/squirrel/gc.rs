@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration as StdDuration;
+
+use chrono::Duration;
+
+use super::daemon;
+use super::event::{get_timestamp_now, Event, EventId, EventTime, Snapshot};
+use super::journal::{Journal, JournalReader, sqlite_journal, PagedJournalQuery};
+use super::snapshot_store::{self, SnapshotStore};
+
+use errors::*;
+
+/// How long snapshots are kept for. Every snapshot is kept for a while, then
+/// only the newest snapshot of each file in each hour, then only the newest
+/// in each day. The most recent snapshot of a file is always kept.
+#[derive(Debug, Clone)]
+pub(crate) struct RetentionPolicy {
+    pub keep_all_for: Duration,
+    pub hourly_for: Duration,
+    pub daily_for: Duration,
+    /// Once the retention periods have been applied, the oldest remaining
+    /// snapshots are removed until the store is no bigger than this
+    pub max_size: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy {
+            keep_all_for: Duration::hours(6),
+            hourly_for: Duration::days(1),
+            daily_for: Duration::days(30),
+            max_size: None,
+        }
+    }
+}
+
+pub(crate) struct GcSchedule {
+    pub interval: StdDuration,
+    pub policy: RetentionPolicy,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct GcReport {
+    pub pruned_events: usize,
+    pub removed_snapshots: usize,
+    pub freed_bytes: u64,
+}
+
+enum Retention {
+    KeepAll,
+    Bucket(String),
+    Expired,
+}
+
+fn retention(policy: &RetentionPolicy, now: &EventTime, timestamp: &EventTime) -> Retention {
+    let age = now.duration_since(timestamp);
+    if age <= policy.keep_all_for {
+        Retention::KeepAll
+    } else if age <= policy.hourly_for {
+        Retention::Bucket(timestamp.format("%Y-%m-%d %H:00"))
+    } else if age <= policy.daily_for {
+        Retention::Bucket(timestamp.format("%Y-%m-%d"))
+    } else {
+        Retention::Expired
+    }
+}
+
+/// The snapshot, followed by the snapshots it's a delta against, all the way
+/// back to a keyframe. `bases` remembers what's been read already.
+fn delta_chain(
+    store: &SnapshotStore,
+    snapshot: &Snapshot,
+    bases: &mut HashMap<Snapshot, Option<Snapshot>>,
+) -> Result<Vec<Snapshot>> {
+    let mut chain = vec![snapshot.clone()];
+    loop {
+        let last = chain.last().unwrap().clone();
+        if !bases.contains_key(&last) {
+            let base = store.delta_base(&last)?.map(|(base, _)| base);
+            bases.insert(last.clone(), base);
+        }
+        match bases[&last] {
+            Some(ref base) if !chain.contains(base) => chain.push(base.clone()),
+            _ => return Ok(chain),
+        }
+    }
+}
+
+/// Picks out the events whose snapshots are no longer wanted. `events` must
+/// be in order, newest first.
+fn select_prunable(
+    events: &[Event],
+    policy: &RetentionPolicy,
+    now: &EventTime,
+    store: &SnapshotStore,
+) -> Result<Vec<EventId>> {
+    let mut seen_paths = HashSet::new();
+    let mut seen_buckets = HashSet::new();
+
+    let mut prunable = Vec::new();
+    let mut kept = Vec::new();
+    for event in events {
+        let path = match event.path() {
+            Some(p) => p,
+            None => continue,
+        };
+        let is_latest = seen_paths.insert(path);
+        if event.snapshot.is_none() {
+            continue;
+        }
+
+        let wanted = match retention(policy, now, &event.timestamp) {
+            Retention::KeepAll => true,
+            Retention::Bucket(bucket) => seen_buckets.insert((path, bucket)),
+            Retention::Expired => false,
+        };
+        if is_latest || wanted {
+            kept.push((event, is_latest));
+        } else {
+            prunable.push(event.event_id.unwrap());
+        }
+    }
+
+    if let Some(max_size) = policy.max_size {
+        // What the kept snapshots take up includes the snapshots their
+        // deltas are based on, and removing one only frees anything once
+        // nothing kept needs it
+        let mut bases = HashMap::new();
+        let mut references: HashMap<Snapshot, usize> = HashMap::new();
+        let mut chains = Vec::with_capacity(kept.len());
+        for &(event, _) in &kept {
+            let chain = delta_chain(store, event.snapshot.as_ref().unwrap(), &mut bases)?;
+            for snapshot in &chain {
+                *references.entry(snapshot.clone()).or_insert(0) += 1;
+            }
+            chains.push(chain);
+        }
+        let mut size: u64 = references.keys().map(|s| store.size_of(s)).sum();
+
+        for (&(event, is_latest), chain) in kept.iter().zip(chains.iter()).rev() {
+            if size <= max_size {
+                break;
+            }
+            if is_latest {
+                continue;
+            }
+
+            prunable.push(event.event_id.unwrap());
+            for snapshot in chain {
+                let count = references.get_mut(snapshot).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    size -= store.size_of(snapshot);
+                }
+            }
+        }
+    }
+
+    Ok(prunable)
+}
+
+pub(super) fn all_events<'a, J>(journal: &'a J) -> Result<Vec<Event>>
+where
+    J: JournalReader<'a>,
+{
+    let mut back = journal.backwards()?;
+    let events = back.next_page()?.collect::<Result<Vec<Event>>>()?;
+    Ok(events)
+}
+
+fn referenced_snapshots(events: &[Event], excluding: &HashSet<EventId>) -> HashSet<Snapshot> {
+    events
+        .iter()
+        .filter(|e| !excluding.contains(&e.event_id.unwrap()))
+        .filter_map(|e| e.snapshot.clone())
+        .collect()
+}
+
+/// Adds the snapshots that deltas in `snapshots` are based on, and so on,
+/// since those can't be removed either
+fn with_delta_bases(store: &SnapshotStore, snapshots: HashSet<Snapshot>) -> Result<HashSet<Snapshot>> {
+    let mut needed = HashSet::new();
+    let mut to_visit: Vec<Snapshot> = snapshots.into_iter().collect();
+    while let Some(snapshot) = to_visit.pop() {
+        if let Some((base, _)) = store.delta_base(&snapshot)? {
+            if !needed.contains(&base) {
+                to_visit.push(base);
+            }
+        }
+        needed.insert(snapshot);
+    }
+    Ok(needed)
+}
+
+/// Nothing else may record events while this runs: a new event can refer to
+/// a snapshot that's about to be removed, since the store doesn't write the
+/// same contents twice. The daemon runs it in between handling changes;
+/// `squirrel gc` only runs while the daemon doesn't, and `restore` and
+/// `checkout` refuse to run while the daemon collects garbage on a schedule.
+pub(crate) fn collect_garbage(
+    stash_path: &Path,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let mut journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+
+    // Everything is worked out before anything is changed, so that nothing
+    // is left half done if the store can't be read
+    let events = all_events(&journal)?;
+    let prunable: HashSet<EventId> = select_prunable(&events, policy, &get_timestamp_now(), &store)?
+        .into_iter()
+        .collect();
+    let needed = with_delta_bases(&store, referenced_snapshots(&events, &prunable))?;
+
+    // Versions which later deltas are based on stay restorable until those
+    // deltas are removed too
+    let pruned: Vec<&Event> = events
+        .iter()
+        .filter(|e| prunable.contains(&e.event_id.unwrap()))
+        .filter(|e| !e.snapshot.as_ref().map_or(false, |s| needed.contains(s)))
+        .collect();
+    let unneeded: HashSet<&Snapshot> = pruned.iter().filter_map(|e| e.snapshot.as_ref()).collect();
+
+    let report = GcReport {
+        pruned_events: pruned.len(),
+        removed_snapshots: unneeded.len(),
+        freed_bytes: unneeded.iter().map(|s| store.size_of(s)).sum(),
+    };
+
+    if !dry_run {
+        let event_ids: Vec<EventId> = pruned.iter().map(|e| e.event_id.unwrap()).collect();
+        journal.prune(&event_ids)?;
+        for snapshot in unneeded {
+            store.remove(snapshot)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Refuses to go on while the daemon collects garbage on a schedule, which
+/// could remove the snapshot a new event is about to refer to
+pub(super) fn ensure_no_scheduled_gc(stash_path: &Path) -> Result<()> {
+    match daemon::running(&stash_path)? {
+        Some(ref info) if info.collects_garbage => Err(ErrorKind::ScheduledGcRunning(info.pid).into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn run_gc(stash_path: &Path, policy: &RetentionPolicy, dry_run: bool) -> Result<()> {
+    if !dry_run {
+        if let Some(info) = daemon::running(&stash_path)? {
+            return Err(ErrorKind::DaemonStillRunning(info.pid).into());
+        }
+    }
+    let report = collect_garbage(&stash_path, policy, dry_run)?;
+    println!(
+        "{} {} snapshots from {} events, freeing {} bytes",
+        if dry_run { "Would remove" } else { "Removed" },
+        report.removed_snapshots,
+        report.pruned_events,
+        report.freed_bytes
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    use squirrel::event::*;
+    use squirrel::fsck;
+    use squirrel::journal::{sqlite_journal, JournalReader, PagedJournalQuery};
+    use squirrel::snapshot_store;
+    use squirrel::squirrel;
+    use super::RetentionPolicy;
+
+    fn at(time: &str) -> EventTime {
+        EventTime::parse(time).unwrap()
+    }
+
+    /// An update of `path` at `time`, with a snapshot of its own
+    fn update(id: EventId, path: &str, time: &str) -> Event {
+        let mut event = new_event(
+            EventType::Update,
+            at(time),
+            Some(Snapshot::from_str(&format!("{:040x}", id), Codec::Gzip)),
+            None,
+            Some(PathBuf::from(path)),
+        );
+        event.event_id = Some(id);
+        event
+    }
+
+    /// The events that would be pruned at 2017-10-21 20:00 under the default
+    /// policy; `events` are given newest first
+    fn prunable(events: &[Event]) -> Vec<EventId> {
+        let stash = TempDir::new("squirrel-gc").unwrap();
+        let store = snapshot_store::new(stash.path());
+        let mut prunable = super::select_prunable(
+            events,
+            &RetentionPolicy::default(),
+            &at("2017-10-21 20:00"),
+            &store,
+        ).unwrap();
+        prunable.sort();
+        prunable
+    }
+
+    #[test]
+    fn the_latest_version_of_a_file_is_always_kept() {
+        let events = vec![
+            update(2, "a.rs", "2017-08-01 12:00"),
+            update(1, "a.rs", "2017-07-01 12:00"),
+        ];
+        assert_eq!(prunable(&events), vec![1]);
+    }
+
+    #[test]
+    fn recent_versions_are_all_kept() {
+        let events = vec![
+            update(3, "a.rs", "2017-10-21 19:59"),
+            update(2, "a.rs", "2017-10-21 19:58"),
+            update(1, "a.rs", "2017-10-21 14:01"),
+        ];
+        assert!(prunable(&events).is_empty());
+    }
+
+    #[test]
+    fn one_version_per_file_is_kept_for_each_hour_then_each_day() {
+        let events = vec![
+            update(9, "a.rs", "2017-10-21 19:00"),
+            // Within the last day: the newest in each hour, for each file
+            update(8, "a.rs", "2017-10-21 10:50"),
+            update(7, "b.rs", "2017-10-21 10:40"),
+            update(6, "a.rs", "2017-10-21 10:20"),
+            update(5, "a.rs", "2017-10-21 09:10"),
+            // Within the last month: the newest in each day
+            update(4, "a.rs", "2017-10-10 18:00"),
+            update(3, "a.rs", "2017-10-10 09:00"),
+            update(2, "a.rs", "2017-10-09 09:00"),
+            // Older than that, gone
+            update(1, "a.rs", "2017-09-01 09:00"),
+        ];
+        assert_eq!(prunable(&events), vec![1, 3, 6]);
+    }
+
+    fn version(n: usize) -> String {
+        (0..200).map(|line| format!("line {} of version {}\n", line, if line == n { n } else { 0 })).collect()
+    }
+
+    #[test]
+    fn snapshots_are_removed_only_once_no_delta_needs_them() {
+        let stash = TempDir::new("squirrel-gc").unwrap();
+        let stash = stash.path();
+        let file = stash.join("file.txt");
+
+        let mut squirrel = squirrel::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        // Versions 0 and 3 are stored whole, the rest as deltas
+        squirrel.set_keyframe_interval(3);
+        for n in 0..5 {
+            File::create(&file).unwrap().write_all(version(n).as_bytes()).unwrap();
+            let event = if n == 0 {
+                FileEvent::Create(file.clone())
+            } else {
+                FileEvent::Write(file.clone())
+            };
+            squirrel.dispatch_event(event).unwrap();
+        }
+
+        // Nothing is wanted but the latest version, which needs version 3
+        let policy = RetentionPolicy {
+            keep_all_for: Duration::zero(),
+            hourly_for: Duration::zero(),
+            daily_for: Duration::zero(),
+            max_size: None,
+        };
+        let report = super::collect_garbage(stash, &policy, false).unwrap();
+        assert_eq!(report.pruned_events, 3);
+        assert_eq!(report.removed_snapshots, 3);
+
+        let journal = sqlite_journal::new(stash).unwrap();
+        let mut forwards = journal.forwards().unwrap();
+        let pruned: Vec<bool> = forwards.next_page().unwrap().map(|e| e.unwrap().pruned).collect();
+        assert_eq!(pruned, vec![true, true, true, false, false]);
+        assert_eq!(fsck::check(stash).unwrap(), vec![]);
+
+        // Nothing more goes until the delta does
+        let report = super::collect_garbage(stash, &policy, false).unwrap();
+        assert_eq!(report.pruned_events, 0);
+        assert_eq!(fsck::check(stash).unwrap(), vec![]);
+    }
+
+    fn stored_size(stash: &Path) -> u64 {
+        let store = snapshot_store::new(stash);
+        store
+            .stored_files()
+            .unwrap()
+            .iter()
+            .map(|stored| ::std::fs::metadata(stored.path()).unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn the_size_limit_counts_the_snapshots_deltas_need() {
+        let stash = TempDir::new("squirrel-gc").unwrap();
+        let stash = stash.path();
+
+        let mut squirrel = squirrel::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        squirrel.set_keyframe_interval(3);
+        for n in 0..5 {
+            let file = stash.join("file.txt");
+            File::create(&file).unwrap().write_all(version(n).as_bytes()).unwrap();
+            squirrel.dispatch_event(FileEvent::Write(file)).unwrap();
+        }
+        let other = stash.join("other.txt");
+        File::create(&other).unwrap().write_all(b"small").unwrap();
+        squirrel.dispatch_event(FileEvent::Write(other)).unwrap();
+
+        // Room for the latest version of each file, but nothing else
+        let store = snapshot_store::new(stash);
+        let journal = sqlite_journal::new(stash).unwrap();
+        let latest = super::with_delta_bases(
+            &store,
+            super::all_events(&journal).unwrap()[..2]
+                .iter()
+                .filter_map(|e| e.snapshot.clone())
+                .collect(),
+        ).unwrap();
+        let max_size: u64 = latest.iter().map(|s| store.size_of(s)).sum();
+        let policy = RetentionPolicy {
+            max_size: Some(max_size),
+            ..RetentionPolicy::default()
+        };
+
+        super::collect_garbage(stash, &policy, false).unwrap();
+        assert_eq!(stored_size(stash), max_size);
+        assert_eq!(fsck::check(stash).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,151 @@
+use rusqlite::{Connection, TransactionBehavior};
+
+use errors::*;
+
+/// The statements which take the schema from one version to the next; the
+/// schema version of a database is the number of these which have been
+/// applied to it. Only ever add to the end of this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original layout. Databases written before schema versions were
+    // recorded already have this table, and start out at version 0.
+    "CREATE TABLE IF NOT EXISTS Events (
+        event_id INTEGER PRIMARY KEY,
+        event_type TEXT,
+        timestamp TEXT,
+        snapshot TEXT,
+        before_path TEXT,
+        after_path TEXT
+    )",
+    // 2: garbage collection
+    "ALTER TABLE Events ADD COLUMN pruned INTEGER NOT NULL DEFAULT 0",
+    // 3: compressed snapshots; anything recorded before this is uncompressed
+    "ALTER TABLE Events ADD COLUMN codec TEXT NOT NULL DEFAULT 'none'",
+    // 4: problems the daemon carried on past
+    "CREATE TABLE Failures (
+        failure_id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        path TEXT,
+        detail TEXT NOT NULL
+    )",
+    // 5: catching up on changes made while the daemon wasn't running
+    "ALTER TABLE Events ADD COLUMN reconciled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE Events ADD COLUMN size INTEGER;
+    ALTER TABLE Events ADD COLUMN mtime TEXT;",
+    // 6: content hashes and permissions; snapshots in the object store are
+    // already named after the hash of their contents
+    "ALTER TABLE Events ADD COLUMN hash TEXT;
+    ALTER TABLE Events ADD COLUMN mode INTEGER;
+    UPDATE Events SET hash = snapshot
+        WHERE length(snapshot) = 40 AND snapshot NOT GLOB '*[^0-9a-f]*';",
+];
+
+pub(super) fn latest_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+pub(super) fn schema_version(connection: &Connection) -> Result<i64> {
+    let version = connection.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Brings the schema up to date, one migration (and transaction) at a time.
+/// Refuses to touch databases written by a newer version of squirrel.
+pub(super) fn migrate(connection: &mut Connection) -> Result<()> {
+    loop {
+        // Take the write lock before looking at the version, in case
+        // something else is opening the journal at the same time
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let version = schema_version(&tx)?;
+        if version > latest_version() {
+            return Err(
+                ErrorKind::UnsupportedSchemaVersion(version, latest_version()).into(),
+            );
+        }
+        if version == latest_version() {
+            return Ok(());
+        }
+
+        debug!("migrating event log to schema version {}", version + 1);
+        tx.execute_batch(MIGRATIONS[version as usize])?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", version + 1))?;
+        tx.commit()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    #[test]
+    fn new_databases_are_brought_up_to_date() {
+        let mut connection = Connection::open_in_memory().unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        assert_eq!(schema_version(&connection).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn unversioned_databases_keep_their_events() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0]).unwrap();
+        connection
+            .execute(
+                "INSERT INTO Events (event_type, timestamp) VALUES ('Update', 'then')",
+                &[],
+            )
+            .unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        let count: i64 = connection
+            .query_row("SELECT COUNT(*) FROM Events", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn hashes_are_filled_in_for_stored_objects() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..5] {
+            connection.execute_batch(migration).unwrap();
+        }
+        connection.execute_batch("PRAGMA user_version = 5").unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO Events (event_type, timestamp, snapshot)
+                    VALUES ('Update', 'then', '0123456789abcdef0123456789abcdef01234567');
+                INSERT INTO Events (event_type, timestamp, snapshot)
+                    VALUES ('Update', 'then', 'hLL2bfCxlbA3eHzUfIhsC-server.rs');",
+            )
+            .unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        let mut statement = connection
+            .prepare("SELECT hash FROM Events ORDER BY event_id")
+            .unwrap();
+        let hashes: Vec<Option<String>> = statement
+            .query_map(&[], |row| row.get(0))
+            .unwrap()
+            .map(|h| h.unwrap())
+            .collect();
+        assert_eq!(
+            hashes,
+            vec![Some("0123456789abcdef0123456789abcdef01234567".to_owned()), None]
+        );
+    }
+
+    #[test]
+    fn databases_from_newer_versions_are_refused() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(&format!("PRAGMA user_version = {}", latest_version() + 1))
+            .unwrap();
+
+        assert!(migrate(&mut connection).is_err());
+    }
+}
@@ -1,12 +1,22 @@
 
 
+use std::path::Path;
+
 use squirrel::event::*;
 use errors::*;
 
+mod migrations;
 pub(crate) mod sqlite_journal;
 
 pub(crate) trait Journal {
     fn journal(&mut self, event: Event) -> Result<()>;
+
+    /// Marks the snapshots of the given events as removed, all at once
+    fn prune(&mut self, event_ids: &[EventId]) -> Result<()>;
+
+    /// Keeps a record of something that went wrong while handling a change,
+    /// which the daemon carried on past
+    fn record_failure(&mut self, timestamp: EventTime, path: Option<&Path>, detail: &str) -> Result<()>;
 }
 
 pub(crate) trait PagedJournalQuery {
@@ -17,6 +27,11 @@ pub(crate) trait PagedJournalQuery {
 
 pub(crate) trait JournalReader<'a> {
     type BackwardsIterator: PagedJournalQuery;
+    type ForwardsIterator: PagedJournalQuery;
 
     fn backwards(&'a self) -> Result<Self::BackwardsIterator>;
+
+    fn forwards(&'a self) -> Result<Self::ForwardsIterator>;
+
+    fn event(&'a self, event_id: EventId) -> Result<Option<Event>>;
 }
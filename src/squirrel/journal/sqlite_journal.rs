@@ -1,63 +1,149 @@
 
 use std::path::{Path, PathBuf};
-use std::result::Result as StdResult;
+use std::thread;
+use std::time::Duration;
 
 use chrono::DateTime;
-use rusqlite::{Connection, Row, Statement, Result as RusqlResult, Error as RusqlError};
+use rusqlite::{Connection, ErrorCode, Row, Statement, Error as RusqlError};
 use rusqlite::types::{FromSql, ValueRef, FromSqlResult, FromSqlError};
 
 use errors::*;
 use squirrel::event::*;
+use super::migrations;
 
 pub(crate) struct SqliteJournal {
     db_connection: Connection,
 }
 
+/// Something the daemon couldn't handle, and carried on past
+#[derive(Debug)]
+pub(crate) struct Failure {
+    pub timestamp: EventTime,
+    pub path: Option<PathBuf>,
+    pub detail: String,
+}
+
 pub(crate) fn new(stash_path: &Path) -> Result<SqliteJournal> {
     let event_log_path = stash_path.join("event-log.db").to_owned();
 
-    let connection = Connection::open(event_log_path)?;
-
-    connection.execute(
-        "CREATE TABLE IF NOT EXISTS Events (
-        event_id INTEGER PRIMARY KEY,
-        event_type TEXT,
-        timestamp TEXT,
-        snapshot TEXT,
-        before_path TEXT,
-        after_path TEXT
-        )",
-        &[],
-    )?;
+    let mut connection = Connection::open(event_log_path)?;
+    migrations::migrate(&mut connection)?;
 
     Ok(SqliteJournal { db_connection: connection })
 }
 
+fn journalling_error(detail: String) -> Error {
+    ErrorKind::EventJournallingError(detail).into()
+}
+
+/// How many times to try a write while something else has the database
+/// locked, and how long to wait before trying again; the wait doubles each
+/// time, so all told this gives up after a little over 3 seconds
+const WRITE_ATTEMPTS: u32 = 7;
+const FIRST_RETRY_DELAY_MS: u64 = 50;
+
+fn is_transient(e: &RusqlError) -> bool {
+    match e {
+        &RusqlError::SqliteFailure(ref failure, _) => {
+            failure.code == ErrorCode::DatabaseBusy || failure.code == ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+fn with_retries<T, F>(mut write: F) -> ::std::result::Result<T, RusqlError>
+where
+    F: FnMut() -> ::std::result::Result<T, RusqlError>,
+{
+    let mut delay = Duration::from_millis(FIRST_RETRY_DELAY_MS);
+    let mut attempt = 1;
+    loop {
+        match write() {
+            Err(ref e) if is_transient(e) && attempt < WRITE_ATTEMPTS => {
+                debug!("the journal is locked ({}); trying again in {:?}", e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 impl<'a> super::Journal for SqliteJournal {
     fn journal(&mut self, event: Event) -> Result<()> {
         let event_type = format!("{}", event.event_type);
         let timestamp = format!("{}", event.timestamp.rfc3339());
-        let snapshot = event.snapshot.map(|p| format!("{}", p.to_string_lossy()));
+        let codec = event.snapshot.as_ref().map(|s| s.codec()).unwrap_or(Codec::None);
+        let codec = format!("{}", codec);
+        let snapshot = event.snapshot.map(|s| format!("{}", s));
         let before_path = event.before_path.map(
             |p| format!("{}", p.to_string_lossy()),
         );
         let after_path = event.after_path.map(|p| format!("{}", p.to_string_lossy()));
+        let size = event.stat.as_ref().map(|s| s.size as i64);
+        let mtime = event.stat.as_ref().map(|s| s.mtime.rfc3339());
+        let mode = event.stat.as_ref().and_then(|s| s.mode).map(|m| m as i64);
+        let reconciled = event.reconciled;
+        let hash = event.hash;
 
-        use std::os::raw::c_int;
-        let _: Result<c_int> = self.db_connection.execute(
-                    &"INSERT INTO Events (event_type, timestamp, snapshot, before_path, after_path) VALUES (?, ?, ?, ?, ?)",
-                    &[&event_type, &timestamp, &snapshot, &before_path, &after_path])
-                    .map_err(|e: RusqlError| {
-                        let provider_msg = format!("{}", e);
-                        ErrorKind::EventJournallingError(format!("Problem writing to database: {}", provider_msg)).into()
-                    });
-
+        let connection = &self.db_connection;
+        with_retries(|| {
+            connection.execute(
+                "INSERT INTO Events (event_type, timestamp, snapshot, codec, before_path, after_path, reconciled, size, mtime, mode, hash)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &event_type,
+                    &timestamp,
+                    &snapshot,
+                    &codec,
+                    &before_path,
+                    &after_path,
+                    &reconciled,
+                    &size,
+                    &mtime,
+                    &mode,
+                    &hash,
+                ],
+            )
+        }).map_err(|e: RusqlError| {
+                journalling_error(format!("Problem writing to database: {}", e))
+            })?;
 
         Ok(())
     }
-}
 
+    fn record_failure(&mut self, timestamp: EventTime, path: Option<&Path>, detail: &str) -> Result<()> {
+        let timestamp = format!("{}", timestamp.rfc3339());
+        let path = path.map(|p| format!("{}", p.to_string_lossy()));
+
+        let connection = &self.db_connection;
+        with_retries(|| {
+            connection.execute(
+                "INSERT INTO Failures (timestamp, path, detail) VALUES (?, ?, ?)",
+                &[&timestamp, &path, &detail],
+            )
+        }).map_err(|e: RusqlError| {
+                journalling_error(format!("Problem recording a failure: {}", e))
+            })?;
 
+        Ok(())
+    }
+
+    fn prune(&mut self, event_ids: &[EventId]) -> Result<()> {
+        let tx = self.db_connection.transaction()?;
+        for event_id in event_ids {
+            tx.execute(
+                "UPDATE Events SET pruned = 1 WHERE event_id = ?",
+                &[event_id],
+            ).map_err(|e: RusqlError| {
+                    journalling_error(format!("Problem pruning event {}: {}", event_id, e))
+                })?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
 
 impl FromSql for EventType {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
@@ -73,67 +159,422 @@ impl FromSql for EventType {
     }
 }
 
-fn row_to_event(row: &Row) -> Event {
-    let after_path: Option<String> = row.get(5);
-    let snapshot: Option<String> = row.get(4);
-    let before_path: Option<String> = row.get(3);
-    let timestamp: String = row.get(2);
-    let timestamp = DateTime::parse_from_rfc3339(&timestamp).unwrap();
-
-    Event {
-        event_id: Some(row.get(0)),
-        event_type: row.get(1),
-        timestamp: EventTime::from_date_time(timestamp),
-        after_path: after_path.map(|s| PathBuf::from(s)),
-        before_path: snapshot.map(|s| PathBuf::from(s)),
-        snapshot: before_path.map(|s| PathBuf::from(s)),
-    }
+fn column<T: FromSql>(row: &Row, name: &str) -> Result<T> {
+    row.get_checked(name).map_err(|e| {
+        journalling_error(format!("Unable to read column {} of the journal: {}", name, e))
+    })
 }
 
-pub(crate) struct QmErrMapper<T>
-where
-    T: Iterator<Item = StdResult<Event, RusqlError>>,
-{
-    qm: T,
-}
+fn row_to_event(row: &Row) -> Result<Event> {
+    let event_id: EventId = column(row, "event_id")?;
+    let event_type: EventType = column(row, "event_type")?;
+    let timestamp: String = column(row, "timestamp")?;
+    let snapshot: Option<String> = column(row, "snapshot")?;
+    let codec: String = column(row, "codec")?;
+    let codec = Codec::from_str(&codec).map_err(|e| {
+        journalling_error(format!("Event {} has an unreadable codec: {}", event_id, e))
+    })?;
+    let before_path: Option<String> = column(row, "before_path")?;
+    let after_path: Option<String> = column(row, "after_path")?;
+    let pruned: bool = column(row, "pruned")?;
+    let reconciled: bool = column(row, "reconciled")?;
+    let size: Option<i64> = column(row, "size")?;
+    let mtime: Option<String> = column(row, "mtime")?;
+    let mode: Option<i64> = column(row, "mode")?;
+    let hash: Option<String> = column(row, "hash")?;
 
-impl<'a, T> Iterator for QmErrMapper<T>
-where
-    T: Iterator<Item = StdResult<Event, RusqlError>>,
-{
-    type Item = Result<Event>;
+    let timestamp = DateTime::parse_from_rfc3339(&timestamp).map_err(|e| {
+        journalling_error(format!(
+            "Event {} has an unreadable timestamp '{}': {}",
+            event_id,
+            timestamp,
+            e
+        ))
+    })?;
+    let stat = match (size, mtime) {
+        (Some(size), Some(mtime)) => Some(FileStat {
+            size: size as u64,
+            mtime: EventTime::parse(&mtime).map_err(|e| {
+                journalling_error(format!(
+                    "Event {} has an unreadable modification time '{}': {}",
+                    event_id,
+                    mtime,
+                    e
+                ))
+            })?,
+            mode: mode.map(|m| m as u32),
+        }),
+        _ => None,
+    };
 
-    fn next(&mut self) -> Option<Result<Event>> {
-        self.qm.next().map(|r| r.map_err(|e| e.into()))
-    }
+    Ok(Event {
+        event_id: Some(event_id),
+        event_type: event_type,
+        timestamp: EventTime::from_date_time(timestamp),
+        snapshot: if pruned {
+            None
+        } else {
+            snapshot.map(|s| Snapshot::from_str(&s, codec))
+        },
+        before_path: before_path.map(PathBuf::from),
+        after_path: after_path.map(PathBuf::from),
+        pruned: pruned,
+        reconciled: reconciled,
+        stat: stat,
+        hash: hash,
+    })
 }
 
 impl<'a> super::PagedJournalQuery for Statement<'a> {
-    type ResultIterator = QmErrMapper<::std::vec::IntoIter<StdResult<Event, RusqlError>>>;
+    type ResultIterator = ::std::vec::IntoIter<Result<Event>>;
 
     fn next_page(&mut self) -> Result<Self::ResultIterator> {
-        let mapper: fn(&Row) -> Event = row_to_event;
-        let qm: Vec<RusqlResult<Event>> = self.query_map(&[], mapper)?.collect();
-        Ok(QmErrMapper { qm: qm.into_iter() })
+        let mapper: fn(&Row) -> Result<Event> = row_to_event;
+        let events: Vec<Result<Event>> = self.query_and_then(&[], mapper)?.collect();
+        Ok(events.into_iter())
     }
 }
 
-impl<'a> super::JournalReader<'a> for SqliteJournal {
-    type BackwardsIterator = Statement<'a>;
-    fn backwards(&'a self) -> Result<Self::BackwardsIterator> {
-        let stmt = self.db_connection.prepare(
+impl SqliteJournal {
+    /// Asks sqlite to check the database file itself for damage. Returns what
+    /// it found wrong, if anything.
+    pub(crate) fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut statement = self.db_connection.prepare("PRAGMA integrity_check")?;
+        let messages = statement
+            .query_map(&[], |row| row.get::<_, String>(0))?
+            .collect::<::std::result::Result<Vec<String>, RusqlError>>()?;
+        Ok(messages.into_iter().filter(|m| m != "ok").collect())
+    }
+
+    /// Every failure recorded, oldest first
+    pub(crate) fn failures(&self) -> Result<Vec<Failure>> {
+        let mut statement = self.db_connection.prepare(
+            "SELECT timestamp, path, detail FROM Failures ORDER BY failure_id",
+        )?;
+        let rows = statement
+            .query_map(&[], |row| {
+                (row.get::<_, String>(0), row.get::<_, Option<String>>(1), row.get::<_, String>(2))
+            })?
+            .collect::<::std::result::Result<Vec<_>, RusqlError>>()?;
+
+        rows.into_iter()
+            .map(|(timestamp, path, detail)| {
+                let parsed = DateTime::parse_from_rfc3339(&timestamp).map_err(|e| {
+                    journalling_error(format!("A failure has an unreadable timestamp '{}': {}", timestamp, e))
+                })?;
+                Ok(Failure {
+                    timestamp: EventTime::from_date_time(parsed),
+                    path: path.map(PathBuf::from),
+                    detail: detail,
+                })
+            })
+            .collect()
+    }
+
+    fn select_events<'a>(&'a self, clauses: &str) -> Result<Statement<'a>> {
+        let stmt = self.db_connection.prepare(&format!(
             "SELECT
                     event_id,
                     event_type,
                     timestamp,
                     snapshot,
+                    codec,
                     before_path,
-                    after_path
+                    after_path,
+                    pruned,
+                    reconciled,
+                    size,
+                    mtime,
+                    mode,
+                    hash
                 FROM
                     Events
-                ORDER BY
-                    timestamp DESC",
-        )?;
+                {}",
+            clauses
+        ))?;
         Ok(stmt)
     }
 }
+
+impl<'a> super::JournalReader<'a> for SqliteJournal {
+    type BackwardsIterator = Statement<'a>;
+    type ForwardsIterator = Statement<'a>;
+
+    fn backwards(&'a self) -> Result<Self::BackwardsIterator> {
+        self.select_events("ORDER BY timestamp DESC, event_id DESC")
+    }
+
+    fn forwards(&'a self) -> Result<Self::ForwardsIterator> {
+        self.select_events("ORDER BY timestamp ASC, event_id ASC")
+    }
+
+    fn event(&'a self, event_id: EventId) -> Result<Option<Event>> {
+        let mut stmt = self.select_events("WHERE event_id = ?")?;
+        let mapper: fn(&Row) -> Result<Event> = row_to_event;
+        let mut events = stmt.query_and_then(&[&event_id], mapper)?;
+        match events.next() {
+            Some(event) => Ok(Some(event?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+
+    use errors::*;
+    use squirrel::event::*;
+    use squirrel::journal::{Journal, JournalReader, PagedJournalQuery};
+
+    fn all_event_types() -> Vec<EventType> {
+        vec![
+            EventType::Create,
+            EventType::Remove,
+            EventType::Update,
+            EventType::Rename,
+            EventType::Restore,
+            EventType::Baseline,
+            EventType::Touch,
+        ]
+    }
+
+    fn event_of_type(event_type: EventType, n: usize) -> Event {
+        new_event(
+            event_type,
+            get_timestamp_now(),
+            Some(Snapshot::from_str(&format!("{:040x}", n), Codec::Gzip)),
+            Some(PathBuf::from(format!("after/{}.rs", n))),
+            Some(PathBuf::from(format!("before/{}.rs", n))),
+        )
+    }
+
+    fn read_back(journal: &super::SqliteJournal) -> Vec<Event> {
+        let mut back = journal.backwards().unwrap();
+        back.next_page().unwrap().map(|e| e.unwrap()).collect()
+    }
+
+    #[test]
+    fn every_event_type_round_trips() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        let written: Vec<Event> = all_event_types()
+            .into_iter()
+            .enumerate()
+            .map(|(n, t)| event_of_type(t, n))
+            .collect();
+        for event in &written {
+            journal.journal(event.clone()).unwrap();
+        }
+
+        let mut read = read_back(&journal);
+        read.reverse();
+
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written.iter()) {
+            assert!(read.event_id.is_some());
+            assert_eq!(read.event_type, written.event_type);
+            assert_eq!(read.snapshot, written.snapshot);
+            assert_eq!(read.before_path, written.before_path);
+            assert_eq!(read.after_path, written.after_path);
+        }
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        let event = event_of_type(EventType::Update, 0);
+        let timestamp = event.timestamp.clone();
+        journal.journal(event).unwrap();
+
+        assert_eq!(read_back(&journal)[0].timestamp, timestamp);
+    }
+
+    #[test]
+    fn missing_fields_round_trip() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        journal
+            .journal(new_event(
+                EventType::Remove,
+                get_timestamp_now(),
+                None,
+                None,
+                Some(PathBuf::from("gone.rs")),
+            ))
+            .unwrap();
+
+        let read = read_back(&journal);
+        assert_eq!(read[0].snapshot, None);
+        assert_eq!(read[0].after_path, None);
+        assert_eq!(read[0].before_path, Some(PathBuf::from("gone.rs")));
+    }
+
+    #[test]
+    fn legacy_snapshot_names_round_trip() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        let legacy = Snapshot::from_str("hLL2bfCxlbA3eHzUfIhsC-server.rs", Codec::None);
+        let mut event = event_of_type(EventType::Update, 0);
+        event.snapshot = Some(legacy.clone());
+        journal.journal(event).unwrap();
+
+        assert_eq!(read_back(&journal)[0].snapshot, Some(legacy));
+    }
+
+    #[test]
+    fn reconciled_changes_keep_their_file_stats() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        let stat = FileStat {
+            size: 1234,
+            mtime: EventTime::parse("2017-10-21T20:34:56.789+00:00").unwrap(),
+            mode: Some(0o755),
+        };
+        let mut event = event_of_type(EventType::Update, 0);
+        event.reconciled = true;
+        event.stat = Some(stat.clone());
+        journal.journal(event).unwrap();
+        journal.journal(event_of_type(EventType::Update, 1)).unwrap();
+
+        let read = read_back(&journal);
+        assert!(!read[0].reconciled);
+        assert_eq!(read[0].stat, None);
+        assert!(read[1].reconciled);
+        assert_eq!(read[1].stat, Some(stat));
+    }
+
+    #[test]
+    fn events_are_read_back_newest_first() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        for n in 0..3 {
+            journal.journal(event_of_type(EventType::Update, n)).unwrap();
+        }
+
+        let ids: Vec<EventId> = read_back(&journal)
+            .iter()
+            .map(|e| e.event_id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn events_can_be_looked_up_by_id() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        journal.journal(event_of_type(EventType::Create, 7)).unwrap();
+
+        let event = journal.event(1).unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Create);
+        assert_eq!(event.after_path, Some(PathBuf::from("after/7.rs")));
+        assert!(journal.event(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn pruned_events_lose_their_snapshot_but_not_its_hash() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        journal.journal(event_of_type(EventType::Update, 0)).unwrap();
+        journal.journal(event_of_type(EventType::Update, 1)).unwrap();
+        journal.prune(&[1]).unwrap();
+
+        let read = read_back(&journal);
+        assert!(!read[0].pruned);
+        assert!(read[0].snapshot.is_some());
+        assert!(read[1].pruned);
+        assert_eq!(read[1].snapshot, None);
+        assert_eq!(read[1].hash, Some(format!("{:040x}", 0)));
+    }
+
+    #[test]
+    fn write_failures_are_reported() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        journal
+            .db_connection
+            .execute("DROP TABLE Events", &[])
+            .unwrap();
+
+        assert!(journal.journal(event_of_type(EventType::Update, 0)).is_err());
+    }
+
+    #[test]
+    fn failures_are_recorded() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let mut journal = super::new(stash.path()).unwrap();
+
+        journal
+            .record_failure(get_timestamp_now(), Some(&PathBuf::from("src/main.rs")), "disk on fire")
+            .unwrap();
+        journal
+            .record_failure(get_timestamp_now(), None, "watcher hiccup")
+            .unwrap();
+
+        let failures: Vec<(Option<PathBuf>, String)> = journal
+            .failures()
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.path, f.detail))
+            .collect();
+
+        assert_eq!(
+            failures,
+            vec![
+                (Some(PathBuf::from("src/main.rs")), "disk on fire".to_owned()),
+                (None, "watcher hiccup".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_rows_are_reported() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let journal = super::new(stash.path()).unwrap();
+
+        journal
+            .db_connection
+            .execute(
+                "INSERT INTO Events (event_type, timestamp) VALUES ('Update', 'yesterday')",
+                &[],
+            )
+            .unwrap();
+
+        let mut back = journal.backwards().unwrap();
+        let result = back.next_page().unwrap().next().unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unreadable_modification_times_are_reported() {
+        let stash = TempDir::new("squirrel-journal").unwrap();
+        let journal = super::new(stash.path()).unwrap();
+
+        journal
+            .db_connection
+            .execute(
+                "INSERT INTO Events (event_type, timestamp, size, mtime)
+                 VALUES ('Update', '2017-10-21T20:34:00+00:00', 4, 'last tuesday')",
+                &[],
+            )
+            .unwrap();
+
+        let mut back = journal.backwards().unwrap();
+        match back.next_page().unwrap().next().unwrap() {
+            Err(Error(ErrorKind::EventJournallingError(_), _)) => (),
+            other => panic!("expected a journalling error, got {:?}", other),
+        }
+    }
+}
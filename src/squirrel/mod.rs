@@ -1,9 +1,31 @@
 
 
 mod server;
-pub(crate) use self::server::run_squirrel;
+mod coalesce;
+#[cfg(unix)]
+mod daemon;
+#[cfg(not(unix))]
+#[path = "daemon_stub.rs"]
+mod daemon;
+mod reconcile;
+pub(crate) use self::daemon::{run_daemon, show_status, stop_daemon};
 mod squirrel;
+pub(crate) use self::squirrel::UnchangedWrites;
 mod event;
 mod journal;
+mod snapshot_store;
+mod delta;
+pub(crate) use self::snapshot_store::DEFAULT_KEYFRAME_INTERVAL;
 mod snapshot_viewer;
-pub(crate) use self::snapshot_viewer::list_snapshots;
+pub(crate) use self::snapshot_viewer::{list_snapshots, show_log};
+mod restore;
+pub(crate) use self::restore::{restore_snapshot, RestoreTarget};
+pub(crate) use self::event::{Codec, EventTime};
+mod checkout;
+pub(crate) use self::checkout::checkout;
+mod snapshot_diff;
+pub(crate) use self::snapshot_diff::diff_snapshots;
+mod gc;
+pub(crate) use self::gc::{run_gc, GcSchedule, RetentionPolicy};
+mod fsck;
+pub(crate) use self::fsck::run_fsck;
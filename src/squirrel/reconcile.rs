@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ignore::WalkBuilder;
+
+use errors::*;
+use path_filter::PathFilter;
+
+use super::checkout::recorded_at;
+use super::event::{file_stat, EventType};
+use super::journal::{self, sqlite_journal};
+use super::squirrel::Squirrel;
+
+/// Every file under `roots` which the filter allows, relative to the watched
+/// directory. The walker's own ignore rules are turned off so that the
+/// filter alone decides, just as it does for changes seen while watching.
+pub(super) fn tracked_files(watched_dir: &Path, roots: &[PathBuf], path_filter: &PathFilter) -> Result<Vec<PathBuf>> {
+    let (first, rest) = match roots.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut walker = WalkBuilder::new(first);
+    for root in rest {
+        walker.add(root);
+    }
+    walker
+        .hidden(false)
+        .parents(false)
+        .ignore(false)
+        .git_ignore(false)
+        .git_global(false)
+        .git_exclude(false);
+
+    let mut files = Vec::new();
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Unable to look at every file while taking a baseline: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().map_or(false, |t| t.is_file()) {
+            continue;
+        }
+        if path_filter.allow(entry.path())? {
+            files.push(entry.path().strip_prefix(watched_dir)?.to_owned());
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Brings the journal up to date with the tree as the daemon finds it on
+/// starting up. Files which look different to what was last recorded (by
+/// size and modification time) are snapshotted, and recorded as reconciled
+/// changes if their contents turn out to differ too; files which have gone
+/// are recorded as removed. The very first time, when the journal knows of no
+/// files at all, everything gets a `Baseline` instead, so that the versions
+/// from before this session can be restored too. Returns the files which are
+/// tracked.
+pub(super) fn reconcile<J>(
+    squirrel: &mut Squirrel<J>,
+    watched_dir: &Path,
+    stash_path: &Path,
+    roots: &[PathBuf],
+    path_filter: &PathFilter,
+) -> Result<Vec<PathBuf>>
+where
+    J: journal::Journal,
+{
+    let recorded = recorded_at(&sqlite_journal::new(&stash_path)?, None)?;
+    squirrel.remember_snapshots(&recorded);
+
+    let files = tracked_files(watched_dir, roots, path_filter)?;
+    let (mut baselines, mut created, mut updated, mut removed) = (0, 0, 0, 0);
+    for file in &files {
+        let event_type = match recorded.get(file) {
+            None if recorded.is_empty() => EventType::Baseline,
+            None => EventType::Create,
+            Some(known) => {
+                if known.stat.is_some() && known.stat == file_stat(&watched_dir.join(file)) {
+                    continue;
+                }
+                if known.snapshot.is_none() {
+                    EventType::Baseline
+                } else {
+                    EventType::Update
+                }
+            }
+        };
+        if squirrel.record_reconciled(file, event_type.clone())? {
+            match event_type {
+                EventType::Baseline => baselines += 1,
+                EventType::Create => created += 1,
+                _ => updated += 1,
+            }
+        }
+    }
+
+    let on_disk: HashSet<&PathBuf> = files.iter().collect();
+    for path in recorded.keys() {
+        let full_path = watched_dir.join(path);
+        if on_disk.contains(path) || fs::symlink_metadata(&full_path).is_ok() {
+            continue;
+        }
+        // Files the filter no longer lets through are left as they were
+        if path_filter.allow(&full_path)? && squirrel.record_reconciled(path, EventType::Remove)? {
+            removed += 1;
+        }
+    }
+
+    info!(
+        "Caught up with {} tracked files: {} created, {} updated and {} removed while stopped; {} baseline snapshots",
+        files.len(),
+        created,
+        updated,
+        removed,
+        baselines
+    );
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+
+    use config::FilterSettings;
+    use path_filter;
+
+    #[test]
+    fn only_files_the_filter_allows_are_tracked() {
+        let watched = TempDir::new("squirrel-reconcile").unwrap();
+        let base = watched.path().canonicalize().unwrap();
+        let stash = base.join(".backup");
+
+        fs::create_dir_all(base.join("src/generated")).unwrap();
+        fs::create_dir_all(stash.join("objects")).unwrap();
+        for file in &["src/main.rs", "src/generated/out.rs", "notes.txt", ".hidden", ".backup/objects/ab"] {
+            File::create(base.join(file)).unwrap();
+        }
+        File::create(base.join(".gitignore"))
+            .unwrap()
+            .write_all(b"generated/\n")
+            .unwrap();
+
+        let settings = FilterSettings {
+            ignore_dotfiles: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            gitignore: true,
+            ignore: true,
+            squirrelignore: true,
+            git_exclude: true,
+            global_gitignore: false,
+        };
+        let filter = path_filter::new(&base, &stash, &settings).unwrap();
+        let roots = vec![base.join("src"), base.join("notes.txt"), base.join(".backup")];
+
+        assert_eq!(
+            super::tracked_files(&base, &roots, &filter).unwrap(),
+            vec![PathBuf::from("notes.txt"), PathBuf::from("src/main.rs")]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::event::{file_stat, Event, EventId, EventTime, EventType, FileEvent, Snapshot};
+use super::gc;
+use super::journal::{JournalReader, sqlite_journal, PagedJournalQuery};
+use super::snapshot_store::{self, SnapshotStore};
+use super::squirrel;
+
+use config::Settings;
+use errors::*;
+
+pub(crate) enum RestoreTarget {
+    Event(EventId),
+    PathAt(PathBuf, EventTime),
+}
+
+pub fn restore_snapshot(
+    watched_dir: &Path,
+    settings: &Settings,
+    target: RestoreTarget,
+    force: bool,
+) -> Result<()> {
+    let stash_path = &settings.stash_path;
+    gc::ensure_no_scheduled_gc(&stash_path)?;
+    let journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+
+    let (event, path) = match target {
+        RestoreTarget::Event(event_id) => {
+            let event = journal.event(event_id)?.ok_or(ErrorKind::NoSuchEvent(event_id))?;
+            let path = event
+                .path()
+                .map(|p| p.to_owned())
+                .ok_or(ErrorKind::NoSnapshot(event_id))?;
+            (event, path)
+        }
+        RestoreTarget::PathAt(path, timestamp) => {
+            let path = relative_path(&watched_dir, &path);
+            // Found under whatever name the file had back then
+            let event = find_version_at(&journal, &path, &timestamp)?.ok_or_else(|| {
+                ErrorKind::NoMatchingEvent(
+                    String::from(path.to_string_lossy()),
+                    format!("{}", timestamp),
+                )
+            })?;
+            (event, path)
+        }
+    };
+
+    let event_id = event.event_id.unwrap();
+    let mode = event.stat.as_ref().and_then(|stat| stat.mode);
+    let snapshot = event.snapshot.ok_or(ErrorKind::NoSnapshot(event_id))?;
+
+    let contents = store.read(&snapshot)?;
+    let destination = watched_dir.join(&path);
+    let same_contents = destination.is_file() && read_contents(&destination)? == contents;
+    let same_mode = mode.is_none() || file_stat(&destination).and_then(|stat| stat.mode) == mode;
+    if same_contents && same_mode {
+        println!("{} already matches event {}", path.to_string_lossy(), event_id);
+        return Ok(());
+    }
+
+    let recorded = match latest_version(&journal, &path)? {
+        Some(Event { event_type: EventType::Remove, .. }) => None,
+        Some(event) => event.snapshot,
+        None => None,
+    };
+    // Only the permissions differ, which loses nothing
+    let unsaved = !same_contents && differs_from_recorded(&store, &destination, recorded.as_ref())?;
+    if unsaved && !force {
+        return Err(
+            ErrorKind::UnsavedChanges(String::from(path.to_string_lossy())).into(),
+        );
+    }
+
+    let mut squirrel = squirrel::configured(&settings, sqlite_journal::new(&stash_path)?)?;
+    if unsaved {
+        // Keep hold of what's being overwritten, so the restore can be undone
+        squirrel.dispatch_event(FileEvent::Write(path.clone()))?;
+    }
+
+    if !same_contents {
+        write_contents(&destination, &contents)?;
+    }
+    if let Some(mode) = mode {
+        set_mode(&destination, mode)?;
+    }
+    squirrel.record_restore(&path, &snapshot)?;
+
+    println!("Restored {} from event {}", path.to_string_lossy(), event_id);
+    Ok(())
+}
+
+pub(super) fn relative_path(watched_dir: &Path, path: &Path) -> PathBuf {
+    let path = path.strip_prefix(watched_dir).unwrap_or(path);
+    path.components()
+        .filter(|c| c != &Component::CurDir)
+        .collect()
+}
+
+/// The last version of the file recorded at or before `timestamp`, following
+/// it back through renames of the file itself or of a directory it's in.
+/// Nothing is found if it had been removed, or renamed away, by then.
+fn find_version_at<'a, J>(journal: &'a J, path: &Path, timestamp: &EventTime) -> Result<Option<Event>>
+where
+    J: JournalReader<'a>,
+{
+    let mut path = path.to_owned();
+    let mut back = journal.backwards()?;
+    for event in back.next_page()? {
+        let event = event?;
+        if event.timestamp > *timestamp {
+            continue;
+        }
+
+        match event.event_type {
+            EventType::Remove => {
+                if event.path().map_or(false, |removed| path.starts_with(removed)) {
+                    return Ok(None);
+                }
+            }
+            EventType::Rename => {
+                let (from, to) = match (&event.before_path, &event.after_path) {
+                    (&Some(ref from), &Some(ref to)) => (from.clone(), to.clone()),
+                    _ => continue,
+                };
+                if to == path && event.snapshot.is_some() {
+                    return Ok(Some(event));
+                }
+                if path.starts_with(&to) {
+                    let rest = path.strip_prefix(&to).unwrap().to_owned();
+                    path = if rest.as_os_str().is_empty() {
+                        from
+                    } else {
+                        from.join(rest)
+                    };
+                } else if path.starts_with(&from) {
+                    return Ok(None);
+                }
+            }
+            _ => {
+                if event.path() == Some(&path) {
+                    return Ok(Some(event));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn latest_version<'a, J>(journal: &'a J, path: &Path) -> Result<Option<Event>>
+where
+    J: JournalReader<'a>,
+{
+    let mut back = journal.backwards()?;
+    for event in back.next_page()? {
+        let event = event?;
+        if event.path() == Some(path) {
+            return Ok(Some(event));
+        }
+    }
+    Ok(None)
+}
+
+pub(super) fn read_contents(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+pub(super) fn write_contents(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    File::create(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// Puts back the permissions the file had when its snapshot was taken
+#[cfg(unix)]
+pub(super) fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(super) fn set_mode(_: &Path, _: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Whether the file on disk holds anything that would be lost by overwriting
+/// it, given the last version of it that was recorded.
+pub(super) fn differs_from_recorded(
+    store: &SnapshotStore,
+    live_file: &Path,
+    recorded: Option<&Snapshot>,
+) -> Result<bool> {
+    if !live_file.is_file() {
+        return Ok(false);
+    }
+
+    let recorded = match recorded {
+        Some(snapshot) if store.contains(snapshot) => snapshot,
+        _ => return Ok(true),
+    };
+
+    Ok(store.read(recorded)? != read_contents(live_file)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use tempdir::TempDir;
+
+    use config::{self, Config, Settings};
+    use errors::*;
+    use squirrel::event::*;
+    use squirrel::journal::{sqlite_journal, Journal, JournalReader, PagedJournalQuery};
+    use squirrel::squirrel;
+    use super::RestoreTarget;
+
+    fn write(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn settings(watched: &Path) -> Settings {
+        let settings = config::load(watched, Config::default()).unwrap();
+        fs::create_dir_all(&settings.stash_path).unwrap();
+        settings
+    }
+
+    /// Records a file being created as "one", then updated to "two"
+    fn one_then_two(watched: &Path, settings: &Settings) -> PathBuf {
+        let file = watched.join("file.txt");
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut squirrel = squirrel::configured(settings, journal).unwrap();
+        write(&file, b"one");
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+        write(&file, b"two");
+        squirrel.dispatch_event(FileEvent::Write(file.clone())).unwrap();
+        file
+    }
+
+    fn recorded(settings: &Settings) -> Vec<Event> {
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut forwards = journal.forwards().unwrap();
+        forwards.next_page().unwrap().map(|e| e.unwrap()).collect()
+    }
+
+    #[test]
+    fn files_are_restored_and_the_restore_recorded() {
+        let watched = TempDir::new("squirrel-restore").unwrap();
+        let settings = settings(watched.path());
+        let file = one_then_two(watched.path(), &settings);
+
+        super::restore_snapshot(watched.path(), &settings, RestoreTarget::Event(1), false).unwrap();
+
+        assert_eq!(super::read_contents(&file).unwrap(), b"one");
+        let types: Vec<EventType> = recorded(&settings).into_iter().map(|e| e.event_type).collect();
+        assert_eq!(types, vec![EventType::Create, EventType::Update, EventType::Restore]);
+    }
+
+    #[test]
+    fn unsaved_changes_are_not_overwritten() {
+        let watched = TempDir::new("squirrel-restore").unwrap();
+        let settings = settings(watched.path());
+        let file = one_then_two(watched.path(), &settings);
+        write(&file, b"three");
+
+        match super::restore_snapshot(watched.path(), &settings, RestoreTarget::Event(1), false) {
+            Err(Error(ErrorKind::UnsavedChanges(_), _)) => (),
+            other => panic!("expected the restore to be refused, got {:?}", other),
+        }
+        assert_eq!(super::read_contents(&file).unwrap(), b"three");
+        assert_eq!(recorded(&settings).len(), 2);
+    }
+
+    #[test]
+    fn forced_restores_record_what_they_overwrite() {
+        let watched = TempDir::new("squirrel-restore").unwrap();
+        let settings = settings(watched.path());
+        let file = one_then_two(watched.path(), &settings);
+        write(&file, b"three");
+
+        super::restore_snapshot(watched.path(), &settings, RestoreTarget::Event(1), true).unwrap();
+
+        assert_eq!(super::read_contents(&file).unwrap(), b"one");
+        let events = recorded(&settings);
+        let types: Vec<EventType> = events.iter().map(|e| e.event_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![EventType::Create, EventType::Update, EventType::Update, EventType::Restore]
+        );
+        let store = ::squirrel::snapshot_store::new(&settings.stash_path);
+        assert_eq!(store.read(events[2].snapshot.as_ref().unwrap()).unwrap(), b"three");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_are_restored_even_if_the_contents_match() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let watched = TempDir::new("squirrel-restore").unwrap();
+        let settings = settings(watched.path());
+        let file = watched.path().join("script.sh");
+        write(&file, b"#!/bin/sh\n");
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        let journal = sqlite_journal::new(&settings.stash_path).unwrap();
+        let mut squirrel = squirrel::configured(&settings, journal).unwrap();
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        super::restore_snapshot(watched.path(), &settings, RestoreTarget::Event(1), false).unwrap();
+
+        assert_eq!(file_stat(&file).unwrap().mode, Some(0o755));
+        assert_eq!(recorded(&settings).last().unwrap().event_type, EventType::Restore);
+    }
+
+    fn at(time: &str) -> EventTime {
+        EventTime::parse(time).unwrap()
+    }
+
+    fn p(path: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(path))
+    }
+
+    /// A journal of the given events, each with a snapshot named after its
+    /// position unless it's a removal or a directory rename
+    fn journal_of(stash: &Path, events: Vec<(EventType, &str, Option<PathBuf>, Option<PathBuf>)>) {
+        let mut journal = sqlite_journal::new(stash).unwrap();
+        for (n, (event_type, time, before, after)) in events.into_iter().enumerate() {
+            let has_snapshot = match event_type {
+                EventType::Remove => false,
+                EventType::Rename => after.as_ref().map_or(false, |p| p.extension().is_some()),
+                _ => true,
+            };
+            let snapshot = if has_snapshot {
+                Some(Snapshot::from_str(&format!("{:040x}", n + 1), Codec::Gzip))
+            } else {
+                None
+            };
+            journal
+                .journal(new_event(event_type, at(time), snapshot, after, before))
+                .unwrap();
+        }
+    }
+
+    fn version_at(stash: &Path, path: &str, time: &str) -> Option<EventId> {
+        let journal = sqlite_journal::new(stash).unwrap();
+        super::find_version_at(&journal, Path::new(path), &at(time))
+            .unwrap()
+            .map(|e| e.event_id.unwrap())
+    }
+
+    #[test]
+    fn versions_are_found_as_they_were_at_the_time() {
+        let stash = TempDir::new("squirrel-restore").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, "2017-10-21 10:00", p("a.rs"), None),
+                (EventType::Update, "2017-10-21 11:00", p("a.rs"), None),
+                (EventType::Remove, "2017-10-21 12:00", p("a.rs"), None),
+                (EventType::Create, "2017-10-21 13:00", p("a.rs"), None),
+            ],
+        );
+
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 09:00"), None);
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 10:30"), Some(1));
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 11:00"), Some(2));
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 12:30"), None);
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 13:30"), Some(4));
+    }
+
+    #[test]
+    fn versions_are_followed_through_renames() {
+        let stash = TempDir::new("squirrel-restore").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, "2017-10-21 10:00", p("a.rs"), None),
+                (EventType::Rename, "2017-10-21 11:00", p("a.rs"), p("b.rs")),
+                (EventType::Create, "2017-10-21 12:00", p("src/c.rs"), None),
+                (EventType::Rename, "2017-10-21 13:00", p("src"), p("lib")),
+            ],
+        );
+
+        assert_eq!(version_at(stash.path(), "b.rs", "2017-10-21 10:30"), None);
+        assert_eq!(version_at(stash.path(), "b.rs", "2017-10-21 11:30"), Some(2));
+        // Gone once it had been renamed
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 10:30"), Some(1));
+        assert_eq!(version_at(stash.path(), "a.rs", "2017-10-21 11:30"), None);
+        // Found under the name it had before its directory was renamed
+        assert_eq!(version_at(stash.path(), "lib/c.rs", "2017-10-21 13:30"), Some(3));
+        assert_eq!(version_at(stash.path(), "lib/c.rs", "2017-10-21 12:30"), None);
+        assert_eq!(version_at(stash.path(), "src/c.rs", "2017-10-21 13:30"), None);
+    }
+}
@@ -1,20 +1,25 @@
 
 use std::io;
 use std::fs;
-use std::time::Duration;
-use std::sync::mpsc::channel as sync_channel;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel as sync_channel, RecvError, RecvTimeoutError};
 use std::path::{Path, PathBuf};
 
 use notify::{watcher, RecursiveMode, Watcher, DebouncedEvent};
 
+use config::Settings;
 use errors::*;
 use path_filter;
 
+use super::coalesce;
+use super::reconcile;
+use super::daemon;
 use super::squirrel;
 use super::event;
+use super::gc::{self, GcSchedule};
 use super::journal;
 
-fn ensure_dir(path: &Path) -> Result<()> {
+pub(crate) fn ensure_dir(path: &Path) -> Result<()> {
     match fs::create_dir(&path) {
         Ok(()) => Ok(()),
         Err(e) => {
@@ -26,56 +31,187 @@ fn ensure_dir(path: &Path) -> Result<()> {
     }
 }
 
-pub(crate) fn run_squirrel(watched_dir: &Path, stash_path: &Path) -> Result<()> {
+fn run_scheduled_gc(stash_path: &Path, schedule: &GcSchedule) {
+    match gc::collect_garbage(&stash_path, &schedule.policy, false) {
+        Ok(report) => info!("garbage collection finished: {:?}", report),
+        Err(e) => warn!("garbage collection failed: {}", e),
+    }
+}
+
+pub(crate) fn run_squirrel(watched_dir: &Path, settings: &Settings) -> Result<()> {
+    let stash_path = &settings.stash_path;
+    let gc_schedule = settings.gc_schedule();
+
     ensure_dir(&stash_path)?;
     let json_journal = journal::sqlite_journal::new(&stash_path)?;
-    let mut squirrel = squirrel::new(&stash_path, json_journal)?;
+    let mut squirrel = squirrel::configured(&settings, json_journal)?;
 
-    let path_filter = path_filter::new(&watched_dir, &stash_path)?;
+    let path_filter = path_filter::new(&watched_dir, &stash_path, &settings.filter)?;
 
     let (change_event_tx, change_event_rx) = sync_channel();
 
-    let mut watcher = watcher(change_event_tx, Duration::from_secs(1)).unwrap();
+    let mut watcher = watcher(change_event_tx, settings.debounce).unwrap();
     watcher
         .watch(&watched_dir, RecursiveMode::Recursive)
         .unwrap();
 
+    let mut scan_roots = Vec::new();
     for top_level in fs::read_dir(watched_dir)? {
         let top_level = top_level?;
 
         let watched = top_level.path();
-        if watched.is_dir() && !path_filter.allow(&watched)? {
+        if watched.is_dir() && !path_filter.allow(&watched)? && !path_filter.may_allow_within(&watched) {
             watcher.unwatch(&watched).expect(&format!(
                 "Unable to unwatch {:?}",
                 watched
             ));
+        } else {
+            scan_roots.push(watched);
         }
     }
 
+    let tracked = reconcile::reconcile(
+        &mut squirrel,
+        &watched_dir,
+        &stash_path,
+        &scan_roots,
+        &path_filter,
+    )?;
+
+    let mut coalescer = coalesce::new(tracked);
+    let mut last_gc = Instant::now();
+    let mut stop_at = None;
     loop {
-        let e = change_event_rx.recv()?;
-        let event = to_squirrel_event(e, &watched_dir)?;
-        let should_fire = {
-            let p = event.path();
-            match p {
-                Some(p) => path_filter.allow(p)?,
-                _ => true,
+        for event in coalescer.expired(Instant::now()) {
+            handle_event(&mut squirrel, &path_filter, event);
+        }
+
+        if stop_at.is_none() && daemon::stop_requested() {
+            info!("Stopping once the last changes have been recorded");
+            stop_at = Some(Instant::now() + settings.debounce + Duration::from_secs(1));
+        }
+        if let Some(stop_at) = stop_at {
+            if Instant::now() >= stop_at {
+                for event in coalescer.flush() {
+                    handle_event(&mut squirrel, &path_filter, event);
+                }
+                info!("Stopped");
+                return Ok(());
             }
+        }
+
+        if let Some(ref schedule) = gc_schedule {
+            if last_gc.elapsed() >= schedule.interval {
+                run_scheduled_gc(&stash_path, schedule);
+                last_gc = Instant::now();
+            }
+        }
+
+        let e = match change_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(e) => e,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Err(RecvError.into()),
         };
-        if should_fire {
-            squirrel.dispatch_event(event)?;
+        if let DebouncedEvent::Error(ref err, ref path) = e {
+            event_failed(&mut squirrel, path.as_ref().map(|p| p.as_path()), &err.to_string());
+            continue;
         }
+
+        let failed_path = event_path(&e).map(|p| p.to_owned());
+        let event = match to_squirrel_event(e, &watched_dir) {
+            Ok(event) => event,
+            Err(err) => {
+                event_failed(&mut squirrel, failed_path.as_ref().map(|p| p.as_path()), &err.to_string());
+                continue;
+            }
+        };
+        notice_change(&path_filter, &event);
+        for event in coalescer.push(event, Instant::now()) {
+            handle_event(&mut squirrel, &path_filter, event);
+        }
+    }
+}
+
+/// Lets the filter know about changes to the ignore files it reads
+fn notice_change(path_filter: &path_filter::PathFilter, event: &event::FileEvent) {
+    match event {
+        &event::FileEvent::Rename(ref source, ref destination) => {
+            path_filter.notice_change(source);
+            path_filter.notice_change(destination);
+        }
+        _ => {
+            if let Some(p) = event.path() {
+                path_filter.notice_change(p);
+            }
+        }
+    }
+}
+
+/// Handles a single change; anything that goes wrong here only affects that
+/// change, so it's recorded and the daemon carries on.
+fn handle_event<J: journal::Journal>(
+    squirrel: &mut squirrel::Squirrel<J>,
+    path_filter: &path_filter::PathFilter,
+    event: event::FileEvent,
+) {
+    let failed_path = event.path().map(|p| p.to_owned());
+    if let Err(err) = dispatch_allowed(squirrel, path_filter, event) {
+        event_failed(squirrel, failed_path.as_ref().map(|p| p.as_path()), &err.to_string());
+    }
+}
+
+fn dispatch_allowed<J: journal::Journal>(
+    squirrel: &mut squirrel::Squirrel<J>,
+    path_filter: &path_filter::PathFilter,
+    event: event::FileEvent,
+) -> Result<()> {
+    let should_fire = {
+        let p = event.path();
+        match p {
+            Some(p) => path_filter.allow(p)?,
+            _ => true,
+        }
+    };
+    if should_fire {
+        squirrel.dispatch_event(event)?;
+    }
+    Ok(())
+}
+
+fn event_path(e: &DebouncedEvent) -> Option<&Path> {
+    match e {
+        &DebouncedEvent::Write(ref p) |
+        &DebouncedEvent::Create(ref p) |
+        &DebouncedEvent::Remove(ref p) |
+        &DebouncedEvent::Rename(ref p, _) => Some(p),
+        _ => None,
+    }
+}
+
+fn event_failed<J: journal::Journal>(squirrel: &mut squirrel::Squirrel<J>, path: Option<&Path>, detail: &str) {
+    match path {
+        Some(p) => warn!("Unable to handle change to {}: {}", p.to_string_lossy(), detail),
+        None => warn!("Unable to handle change: {}", detail),
+    }
+    if let Err(e) = squirrel.record_failure(path, detail) {
+        warn!("Unable to record failure in the journal: {}", e);
     }
 }
 
 fn relativize<'a>(base_path: &'a Path, abs_path: &'a Path) -> Result<PathBuf> {
     abs_path
         .strip_prefix(base_path)
-        .chain_err(|| "o shit")
+        .chain_err(|| {
+            format!(
+                "{} is outside the watched directory {}",
+                abs_path.to_string_lossy(),
+                base_path.to_string_lossy()
+            )
+        })
         .map(|p| p.to_owned())
 }
 
-fn to_squirrel_event(notify_event: DebouncedEvent, base_path: &Path) -> Result<event::FileEvent> {
+pub(super) fn to_squirrel_event(notify_event: DebouncedEvent, base_path: &Path) -> Result<event::FileEvent> {
 
     Ok(match notify_event {
         DebouncedEvent::Write(p) => event::FileEvent::Write(relativize(&base_path, &p)?),
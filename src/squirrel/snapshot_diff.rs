@@ -0,0 +1,409 @@
+use std::cmp;
+use std::path::{Path, PathBuf};
+
+use diff;
+
+use super::delta::MAX_DIFF_CELLS;
+use super::event::EventId;
+use super::journal::{JournalReader, sqlite_journal};
+use super::restore::read_contents;
+use super::snapshot_store::{self, SnapshotStore};
+
+use errors::*;
+
+const CONTEXT_LINES: usize = 3;
+const STAT_WIDTH: usize = 50;
+
+/// One side of a comparison: a recorded snapshot, or the file as it is now
+struct Version {
+    path: PathBuf,
+    label: String,
+    contents: Option<Vec<u8>>,
+}
+
+impl Version {
+    fn of_event<'a, J>(journal: &'a J, store: &SnapshotStore, event_id: EventId) -> Result<Version>
+    where
+        J: JournalReader<'a>,
+    {
+        let event = journal.event(event_id)?.ok_or(
+            ErrorKind::NoSuchEvent(event_id),
+        )?;
+        let path = event.path().map(|p| p.to_owned()).ok_or(
+            ErrorKind::NoSnapshot(event_id),
+        )?;
+        let snapshot = event.snapshot.as_ref().ok_or(
+            ErrorKind::NoSnapshot(event_id),
+        )?;
+
+        Ok(Version {
+            path: path,
+            label: format!("event {}, {}", event_id, event.timestamp),
+            contents: Some(store.read(snapshot)?),
+        })
+    }
+
+    fn live(watched_dir: &Path, path: &Path) -> Result<Version> {
+        let live_file = watched_dir.join(path);
+        let contents = if live_file.is_file() {
+            Some(read_contents(&live_file)?)
+        } else {
+            None
+        };
+
+        Ok(Version {
+            path: path.to_owned(),
+            label: String::from("working copy"),
+            contents: contents,
+        })
+    }
+
+    fn name(&self, prefix: &str) -> String {
+        match self.contents {
+            Some(_) => format!("{}{}", prefix, self.path.to_string_lossy()),
+            None => String::from("/dev/null"),
+        }
+    }
+
+    fn header(&self, prefix: &str) -> String {
+        match self.contents {
+            Some(_) => format!("{}\t({})", self.name(prefix), self.label),
+            None => self.name(prefix),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.contents.as_ref().map(|c| c.as_slice()).unwrap_or(&[])
+    }
+}
+
+/// Files with a NUL byte near the start are treated as binary, the same
+/// heuristic git uses.
+pub(super) fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|b| *b == 0)
+}
+
+/// Splits text into lines, each keeping its line ending (if it has one)
+pub(super) fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while let Some(end) = rest.find('\n') {
+        let (line, remainder) = rest.split_at(end + 1);
+        lines.push(line);
+        rest = remainder;
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    lines
+}
+
+pub fn diff_snapshots(
+    watched_dir: &Path,
+    stash_path: &Path,
+    from: EventId,
+    to: Option<EventId>,
+    stat: bool,
+) -> Result<()> {
+    let journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+
+    let from = Version::of_event(&journal, &store, from)?;
+    let to = match to {
+        Some(to) => Version::of_event(&journal, &store, to)?,
+        None => Version::live(&watched_dir, &from.path)?,
+    };
+
+    if from.contents == to.contents {
+        return Ok(());
+    }
+
+    if is_binary(from.bytes()) || is_binary(to.bytes()) {
+        if stat {
+            println!(
+                " {} | Bin {} -> {} bytes",
+                to.path.to_string_lossy(),
+                from.bytes().len(),
+                to.bytes().len()
+            );
+        } else {
+            println!(
+                "Binary files {} and {} differ",
+                from.name("a/"),
+                to.name("b/")
+            );
+        }
+        return Ok(());
+    }
+
+    let from_text = String::from_utf8_lossy(from.bytes());
+    let to_text = String::from_utf8_lossy(to.bytes());
+    let from_lines = split_lines(&from_text);
+    let to_lines = split_lines(&to_text);
+    let changes = match line_changes(&from_lines, &to_lines) {
+        Some(changes) => changes,
+        None => {
+            if stat {
+                println!(
+                    " {} | {} -> {} lines",
+                    to.path.to_string_lossy(),
+                    from_lines.len(),
+                    to_lines.len()
+                );
+            } else {
+                println!(
+                    "Files {} and {} differ (too many lines to compare)",
+                    from.name("a/"),
+                    to.name("b/")
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    if stat {
+        print_stat(&to, &changes);
+    } else {
+        println!("--- {}", from.header("a/"));
+        println!("+++ {}", to.header("b/"));
+        for line in hunks(&changes) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Compares the lines of two versions, unless there are so many that the
+/// comparison would take too much memory
+fn line_changes<'a>(from_lines: &'a [&'a str], to_lines: &'a [&'a str]) -> Option<Vec<diff::Result<&'a &'a str>>> {
+    if from_lines.len().saturating_mul(to_lines.len()) > MAX_DIFF_CELLS {
+        return None;
+    }
+    Some(diff::slice(from_lines, to_lines))
+}
+
+fn print_stat(to: &Version, changes: &[diff::Result<&&str>]) {
+    let mut insertions = 0;
+    let mut deletions = 0;
+    for change in changes {
+        match change {
+            &diff::Result::Left(_) => deletions += 1,
+            &diff::Result::Right(_) => insertions += 1,
+            &diff::Result::Both(_, _) => {}
+        }
+    }
+
+    // Keep the graph to a sensible width, like git does
+    let changed = insertions + deletions;
+    let (plus, minus) = if changed > STAT_WIDTH {
+        // Rounded, but never hiding one side of the change altogether
+        let plus = (insertions * STAT_WIDTH + changed / 2) / changed;
+        let plus = cmp::max(plus, cmp::min(insertions, 1));
+        let plus = cmp::min(plus, STAT_WIDTH - cmp::min(deletions, 1));
+        (plus, STAT_WIDTH - plus)
+    } else {
+        (insertions, deletions)
+    };
+
+    println!(
+        " {} | {} {}{}",
+        to.path.to_string_lossy(),
+        changed,
+        "+".repeat(plus),
+        "-".repeat(minus)
+    );
+    println!(
+        " 1 file changed, {} insertions(+), {} deletions(-)",
+        insertions,
+        deletions
+    );
+}
+
+fn push_line(output: &mut Vec<String>, marker: char, line: &str) {
+    if line.ends_with('\n') {
+        output.push(format!("{}{}", marker, &line[..line.len() - 1]));
+    } else {
+        output.push(format!("{}{}", marker, line));
+        output.push(String::from("\\ No newline at end of file"));
+    }
+}
+
+fn hunk_range(start: usize, count: usize) -> String {
+    // An empty range refers to the line before it, as diff(1) does
+    let start = if count == 0 { start } else { start + 1 };
+    format!("{},{}", start, count)
+}
+
+/// The changes as unified diff hunks, one line of output at a time
+fn hunks(changes: &[diff::Result<&&str>]) -> Vec<String> {
+    let mut output = Vec::new();
+    let changed: Vec<usize> = changes
+        .iter()
+        .enumerate()
+        .filter(|&(_, c)| match c {
+            &diff::Result::Both(_, _) => false,
+            _ => true,
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    // Line numbers (in the old and new file) at which each change starts
+    let mut positions = Vec::with_capacity(changes.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for change in changes {
+        positions.push((old_line, new_line));
+        match change {
+            &diff::Result::Left(_) => old_line += 1,
+            &diff::Result::Right(_) => new_line += 1,
+            &diff::Result::Both(_, _) => {
+                old_line += 1;
+                new_line += 1;
+            }
+        }
+    }
+    positions.push((old_line, new_line));
+
+    let mut i = 0;
+    while i < changed.len() {
+        // Group together changes whose context would overlap
+        let mut j = i;
+        while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * CONTEXT_LINES + 1 {
+            j += 1;
+        }
+
+        let start = changed[i].saturating_sub(CONTEXT_LINES);
+        let end = cmp::min(changes.len(), changed[j] + CONTEXT_LINES + 1);
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+
+        output.push(format!(
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start)
+        ));
+        for change in &changes[start..end] {
+            match change {
+                &diff::Result::Left(line) => push_line(&mut output, '-', line),
+                &diff::Result::Right(line) => push_line(&mut output, '+', line),
+                &diff::Result::Both(line, _) => push_line(&mut output, ' ', line),
+            }
+        }
+
+        i = j + 1;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use diff;
+
+    fn lines(n: usize) -> Vec<String> {
+        (1..n + 1).map(|i| format!("{}\n", i)).collect()
+    }
+
+    fn hunks_between(from: &[String], to: &[String]) -> Vec<String> {
+        let from: Vec<&str> = from.iter().map(|l| l.as_str()).collect();
+        let to: Vec<&str> = to.iter().map(|l| l.as_str()).collect();
+        let changes = super::line_changes(&from, &to).unwrap();
+        super::hunks(&changes)
+    }
+
+    #[test]
+    fn lines_keep_their_endings() {
+        assert_eq!(super::split_lines("a\nb\n"), vec!["a\n", "b\n"]);
+        assert_eq!(super::split_lines("a\nb"), vec!["a\n", "b"]);
+        assert_eq!(super::split_lines("\n\n"), vec!["\n", "\n"]);
+        assert!(super::split_lines("").is_empty());
+    }
+
+    #[test]
+    fn only_contents_with_nul_bytes_near_the_start_are_binary() {
+        assert!(!super::is_binary(b"fn main() {}\n"));
+        assert!(!super::is_binary("caf\u{e9}\n".as_bytes()));
+        assert!(super::is_binary(b"\x7fELF\x02\x01\x01\x00"));
+
+        let mut late_nul = vec![b'a'; 8000];
+        late_nul.push(0);
+        assert!(!super::is_binary(&late_nul));
+    }
+
+    #[test]
+    fn empty_ranges_refer_to_the_line_before() {
+        assert_eq!(super::hunk_range(0, 3), "1,3");
+        assert_eq!(super::hunk_range(4, 2), "5,2");
+        assert_eq!(super::hunk_range(4, 0), "4,0");
+    }
+
+    #[test]
+    fn changes_are_shown_with_three_lines_of_context() {
+        let from = lines(10);
+        let mut to = from.clone();
+        to[4] = String::from("five\n");
+
+        assert_eq!(
+            hunks_between(&from, &to),
+            vec!["@@ -2,7 +2,7 @@", " 2", " 3", " 4", "-5", "+five", " 6", " 7", " 8"]
+        );
+    }
+
+    #[test]
+    fn nearby_changes_share_a_hunk() {
+        let from = lines(20);
+        let mut to = from.clone();
+        to[2] = String::from("three\n");
+        // Six unchanged lines apart, so their context overlaps
+        to[9] = String::from("ten\n");
+        to.remove(18);
+
+        assert_eq!(
+            hunks_between(&from, &to),
+            vec![
+                "@@ -1,13 +1,13 @@",
+                " 1",
+                " 2",
+                "-3",
+                "+three",
+                " 4",
+                " 5",
+                " 6",
+                " 7",
+                " 8",
+                " 9",
+                "-10",
+                "+ten",
+                " 11",
+                " 12",
+                " 13",
+                "@@ -16,5 +16,4 @@",
+                " 16",
+                " 17",
+                " 18",
+                "-19",
+                " 20",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_newlines_at_the_end_are_marked() {
+        let from = vec![String::from("a\n"), String::from("b")];
+        let to = vec![String::from("a\n"), String::from("b\n")];
+
+        assert_eq!(
+            hunks_between(&from, &to),
+            vec!["@@ -1,2 +1,2 @@", " a", "-b", "\\ No newline at end of file", "+b"]
+        );
+    }
+
+    #[test]
+    fn too_many_lines_are_not_compared() {
+        let from = lines(2001);
+        let from: Vec<&str> = from.iter().map(|l| l.as_str()).collect();
+        let to = from.clone();
+        assert!(super::line_changes(&from, &to).is_none());
+
+        let changes: Vec<diff::Result<&&str>> = super::line_changes(&from[..2000], &to[..2000]).unwrap();
+        assert_eq!(changes.len(), 2000);
+    }
+}
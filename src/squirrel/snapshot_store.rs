@@ -0,0 +1,422 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha1::Sha1;
+
+use super::delta;
+use super::event::{Codec, Snapshot};
+use errors::*;
+
+/// Keeps the contents of snapshotted files under `objects/` in the stash,
+/// named after the hash of their contents, so that saving the same contents
+/// twice only stores them once. New snapshots are compressed with `codec`.
+///
+/// A snapshot may be stored as a delta against the previous snapshot of the
+/// same file, rather than whole. At most `keyframe_interval - 1` deltas are
+/// chained together before a snapshot is stored whole again, which bounds
+/// the work needed to read one back.
+pub(crate) struct SnapshotStore {
+    stash_path: PathBuf,
+    codec: Codec,
+    keyframe_interval: usize,
+}
+
+pub(crate) fn new(stash_path: &Path) -> SnapshotStore {
+    SnapshotStore {
+        stash_path: stash_path.to_owned(),
+        codec: Codec::Gzip,
+        keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+    }
+}
+
+pub(crate) const DEFAULT_KEYFRAME_INTERVAL: usize = 10;
+
+const DELTA_MAGIC: &str = "squirrel-delta";
+
+const ALL_CODECS: &[Codec] = &[Codec::None, Codec::Gzip];
+
+fn extension(codec: Codec) -> &'static str {
+    match codec {
+        Codec::None => "",
+        Codec::Gzip => ".gz",
+    }
+}
+
+fn encode(contents: &[u8], codec: Codec) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(contents.to_owned()),
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+fn decode(encoded: Vec<u8>, codec: Codec) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(encoded),
+        Codec::Gzip => {
+            let mut contents = Vec::new();
+            GzDecoder::new(encoded.as_slice()).read_to_end(&mut contents)?;
+            Ok(contents)
+        }
+    }
+}
+
+/// Objects are written under a temporary name first, so that one is either
+/// there in full or not at all. Anything left with this prefix was being
+/// written when squirrel stopped.
+pub(crate) const TEMP_PREFIX: &str = ".tmp-";
+
+/// Writes an object to disk and makes sure it's there to stay before
+/// returning, so that the journal never refers to a snapshot which a crash
+/// could still take away
+fn write_object(path: &Path, contents: &[u8], codec: Codec) -> Result<()> {
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+
+    let encoded = encode(contents, codec)?;
+    let temp_path = dir.join(format!(
+        "{}{}-{}",
+        TEMP_PREFIX,
+        process::id(),
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(&encoded)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn read_object(path: &Path, codec: Codec) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    File::open(path)?.read_to_end(&mut encoded)?;
+    decode(encoded, codec)
+}
+
+/// Splits a stored delta into the snapshot it is based on, its depth and
+/// its instructions
+fn parse_delta(mut delta: &[u8]) -> Result<(Snapshot, usize, &[u8])> {
+    let unreadable = || -> Error {
+        ErrorKind::CorruptSnapshot("delta has an unreadable header".to_owned()).into()
+    };
+
+    if delta::take_line(&mut delta)? != DELTA_MAGIC {
+        return Err(unreadable());
+    }
+    let header = delta::take_line(&mut delta)?;
+    let mut words = header.split(' ');
+    let base_hash = words.next().ok_or_else(&unreadable)?;
+    let base_codec = Codec::from_str(words.next().ok_or_else(&unreadable)?)?;
+    let depth = words.next().and_then(|d| d.parse().ok()).ok_or_else(
+        &unreadable,
+    )?;
+
+    Ok((Snapshot::Object(base_hash.to_owned(), base_codec), depth, delta))
+}
+
+pub(crate) fn content_hash(contents: &[u8]) -> String {
+    Sha1::from(contents).digest().to_string()
+}
+
+impl SnapshotStore {
+    fn object_path(&self, hash: &str, codec: Codec) -> PathBuf {
+        let (fan_out, rest) = hash.split_at(2);
+        self.stash_path
+            .join("objects")
+            .join(fan_out)
+            .join(format!("{}{}", rest, extension(codec)))
+    }
+
+    fn delta_path(&self, hash: &str, codec: Codec) -> PathBuf {
+        let (fan_out, rest) = hash.split_at(2);
+        self.stash_path
+            .join("objects")
+            .join(fan_out)
+            .join(format!("{}.delta{}", rest, extension(codec)))
+    }
+
+    pub(crate) fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub(crate) fn set_keyframe_interval(&mut self, keyframe_interval: usize) {
+        self.keyframe_interval = keyframe_interval;
+    }
+
+    /// Where the contents of a snapshot can be found on disk. Snapshots taken
+    /// before the object store existed are still read from where they were
+    /// written.
+    pub(crate) fn path_of(&self, snapshot: &Snapshot) -> PathBuf {
+        match snapshot {
+            &Snapshot::Object(ref hash, codec) => {
+                let delta_path = self.delta_path(hash, codec);
+                if delta_path.is_file() {
+                    delta_path
+                } else {
+                    self.object_path(hash, codec)
+                }
+            }
+            &Snapshot::Legacy(ref name) => self.stash_path.join(name),
+        }
+    }
+
+    pub(crate) fn contains(&self, snapshot: &Snapshot) -> bool {
+        self.path_of(snapshot).is_file()
+    }
+
+    /// Saves the contents of `source_file`, as a delta against `previous` (the
+    /// last snapshot of the same file) if that is worthwhile.
+    pub(crate) fn store(&self, source_file: &Path, previous: Option<&Snapshot>) -> Result<Snapshot> {
+        let mut contents = Vec::new();
+        File::open(source_file)?.read_to_end(&mut contents)?;
+
+        let hash = content_hash(&contents);
+
+        // The same contents may already be stored, perhaps with another codec
+        for &codec in ALL_CODECS {
+            let snapshot = Snapshot::Object(hash.clone(), codec);
+            if self.contains(&snapshot) {
+                return Ok(snapshot);
+            }
+        }
+
+        if let Some(delta) = previous.and_then(|p| self.delta_against(p, &contents)) {
+            write_object(&self.delta_path(&hash, self.codec), &delta, self.codec)?;
+        } else {
+            write_object(&self.object_path(&hash, self.codec), &contents, self.codec)?;
+        }
+
+        Ok(Snapshot::Object(hash, self.codec))
+    }
+
+    /// A delta turning `base` into `contents`, complete with its header, if
+    /// it would be smaller than `contents` and wouldn't make the chain of
+    /// deltas too long
+    fn delta_against(&self, base: &Snapshot, contents: &[u8]) -> Option<Vec<u8>> {
+        let (base_hash, base_codec) = match base {
+            &Snapshot::Object(ref hash, codec) => (hash, codec),
+            &Snapshot::Legacy(_) => return None,
+        };
+        let depth = match self.delta_base(base).ok()? {
+            Some((_, base_depth)) => base_depth + 1,
+            None => 1,
+        };
+        if depth >= self.keyframe_interval {
+            return None;
+        }
+
+        let instructions = delta::compute(&self.read(base).ok()?, contents)?;
+        let mut delta = format!("{}\n{} {} {}\n", DELTA_MAGIC, base_hash, base_codec, depth).into_bytes();
+        delta.extend_from_slice(&instructions);
+
+        if delta.len() < contents.len() {
+            Some(delta)
+        } else {
+            None
+        }
+    }
+
+    /// Reads the header of a snapshot stored as a delta, giving the snapshot
+    /// it is based on and how many deltas deep it is. Keyframes have no base.
+    pub(crate) fn delta_base(&self, snapshot: &Snapshot) -> Result<Option<(Snapshot, usize)>> {
+        let (hash, codec) = match snapshot {
+            &Snapshot::Object(ref hash, codec) => (hash, codec),
+            &Snapshot::Legacy(_) => return Ok(None),
+        };
+        let delta_path = self.delta_path(hash, codec);
+        if !delta_path.is_file() {
+            return Ok(None);
+        }
+
+        let delta = read_object(&delta_path, codec)?;
+        let (base, depth, _) = parse_delta(&delta)?;
+        Ok(Some((base, depth)))
+    }
+
+    pub(crate) fn read(&self, snapshot: &Snapshot) -> Result<Vec<u8>> {
+        if let &Snapshot::Object(ref hash, codec) = snapshot {
+            let delta_path = self.delta_path(hash, codec);
+            if delta_path.is_file() {
+                let delta = read_object(&delta_path, codec)?;
+                let (base, _, instructions) = parse_delta(&delta)?;
+                return delta::apply(&self.read(&base)?, instructions);
+            }
+        }
+        read_object(&self.path_of(snapshot), snapshot.codec())
+    }
+
+    /// How much space a snapshot takes up on disk
+    pub(crate) fn size_of(&self, snapshot: &Snapshot) -> u64 {
+        fs::metadata(self.path_of(snapshot))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn remove(&self, snapshot: &Snapshot) -> Result<()> {
+        fs::remove_file(self.path_of(snapshot))?;
+        Ok(())
+    }
+
+    /// Everything under `objects/`, whether or not the journal refers to it
+    pub(crate) fn stored_files(&self) -> Result<Vec<StoredFile>> {
+        let objects = self.stash_path.join("objects");
+        if !objects.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut stored = Vec::new();
+        for fan_out in fs::read_dir(&objects)? {
+            let fan_out = fan_out?;
+            if !fan_out.file_type()?.is_dir() {
+                stored.push(StoredFile::Unrecognised(fan_out.path()));
+                continue;
+            }
+            for file in fs::read_dir(fan_out.path())? {
+                let path = file?.path();
+                stored.push(classify(&fan_out.file_name().to_string_lossy(), path));
+            }
+        }
+        stored.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(stored)
+    }
+}
+
+/// A file found in the object store
+#[derive(Debug, PartialEq)]
+pub(crate) enum StoredFile {
+    Snapshot(Snapshot, PathBuf),
+    /// Left behind by a write that never finished
+    Temporary(PathBuf),
+    Unrecognised(PathBuf),
+}
+
+impl StoredFile {
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            &StoredFile::Snapshot(_, ref path) |
+            &StoredFile::Temporary(ref path) |
+            &StoredFile::Unrecognised(ref path) => path,
+        }
+    }
+}
+
+fn classify(fan_out: &str, path: PathBuf) -> StoredFile {
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    if name.starts_with(TEMP_PREFIX) {
+        return StoredFile::Temporary(path);
+    }
+
+    let (rest, codec) = if name.ends_with(extension(Codec::Gzip)) {
+        (&name[..name.len() - extension(Codec::Gzip).len()], Codec::Gzip)
+    } else {
+        (&name[..], Codec::None)
+    };
+    let rest = rest.trim_right_matches(".delta");
+    let hash = format!("{}{}", fan_out, rest);
+    if hash.len() == 40 && hash.chars().all(|c| c.is_digit(16)) {
+        StoredFile::Snapshot(Snapshot::Object(hash, codec), path)
+    } else {
+        StoredFile::Unrecognised(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use squirrel::event::Snapshot;
+    use super::StoredFile;
+
+    fn save(store: &super::SnapshotStore, dir: &TempDir, contents: &str, previous: Option<&Snapshot>) -> Snapshot {
+        let path = dir.path().join("file.txt");
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        store.store(&path, previous).unwrap()
+    }
+
+    fn version(n: usize) -> String {
+        (0..200).map(|line| format!("line {} of version {}\n", line, if line == n { n } else { 0 })).collect()
+    }
+
+    #[test]
+    fn deltas_read_back_transparently() {
+        let stash = TempDir::new("squirrel-store").unwrap();
+        let store = super::new(stash.path());
+
+        let first = save(&store, &stash, &version(0), None);
+        let second = save(&store, &stash, &version(1), Some(&first));
+
+        assert!(store.delta_base(&first).unwrap().is_none());
+        assert_eq!(store.delta_base(&second).unwrap().unwrap().0, first);
+        assert!(store.size_of(&second) < store.size_of(&first));
+        assert_eq!(store.read(&second).unwrap(), version(1).into_bytes());
+    }
+
+    #[test]
+    fn keyframes_bound_the_chain_of_deltas() {
+        let stash = TempDir::new("squirrel-store").unwrap();
+        let mut store = super::new(stash.path());
+        store.set_keyframe_interval(3);
+
+        let mut snapshots = vec![save(&store, &stash, &version(0), None)];
+        for n in 1..7 {
+            let previous = snapshots.last().cloned();
+            snapshots.push(save(&store, &stash, &version(n), previous.as_ref()));
+        }
+
+        let depths: Vec<usize> = snapshots
+            .iter()
+            .map(|s| store.delta_base(s).unwrap().map_or(0, |(_, depth)| depth))
+            .collect();
+        assert_eq!(depths, vec![0, 1, 2, 0, 1, 2, 0]);
+        for (n, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(store.read(snapshot).unwrap(), version(n).into_bytes());
+        }
+    }
+
+    #[test]
+    fn stored_files_are_found_and_classified() {
+        let stash = TempDir::new("squirrel-store").unwrap();
+        let store = super::new(stash.path());
+
+        let first = save(&store, &stash, &version(0), None);
+        let second = save(&store, &stash, &version(1), Some(&first));
+        let leftover = stash.path().join("objects/00/.tmp-1-0000.gz");
+        fs::create_dir_all(leftover.parent().unwrap()).unwrap();
+        File::create(&leftover).unwrap();
+
+        let stored = store.stored_files().unwrap();
+        assert_eq!(stored.len(), 3);
+        assert!(stored.contains(&StoredFile::Snapshot(first.clone(), store.path_of(&first))));
+        assert!(stored.contains(&StoredFile::Snapshot(second.clone(), store.path_of(&second))));
+        assert!(stored.contains(&StoredFile::Temporary(leftover)));
+    }
+
+    #[test]
+    fn a_keyframe_interval_of_one_stores_everything_whole() {
+        let stash = TempDir::new("squirrel-store").unwrap();
+        let mut store = super::new(stash.path());
+        store.set_keyframe_interval(1);
+
+        let first = save(&store, &stash, &version(0), None);
+        let second = save(&store, &stash, &version(1), Some(&first));
+
+        assert!(store.delta_base(&second).unwrap().is_none());
+    }
+}
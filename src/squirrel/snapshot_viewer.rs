@@ -1,15 +1,19 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use glob::Pattern;
 
-use super::event::Event;
+use super::event::{Event, EventType};
 use super::journal::{JournalReader, sqlite_journal, PagedJournalQuery};
+use super::restore::relative_path;
+use super::snapshot_diff::{is_binary, split_lines};
+use super::snapshot_store::{self, SnapshotStore};
 
 use errors::*;
 
-pub fn list_snapshots(stash_path: &Path, glob: Pattern) -> Result<()> {
+pub fn list_snapshots(stash_path: &Path, glob: Pattern, long: bool) -> Result<()> {
     let viewer = SnapshotViewer {
         journal: sqlite_journal::new(&stash_path)?,
         glob: glob,
+        long: long,
     };
 
     viewer.show_relevant_snapshots()?;
@@ -19,6 +23,9 @@ pub fn list_snapshots(stash_path: &Path, glob: Pattern) -> Result<()> {
 struct SnapshotViewer<J> {
     journal: J,
     glob: Pattern,
+    /// Also show what was recorded about each file: its size, permissions,
+    /// modification time and content hash
+    long: bool,
 }
 
 fn match_name(glob: &Pattern, event: &Event) -> Option<String> {
@@ -35,31 +42,87 @@ fn match_name(glob: &Pattern, event: &Event) -> Option<String> {
     })
 }
 
+/// Changes found when the daemon started up, rather than seen as they
+/// happened, are marked with a `*`
+fn update_type(event: &Event) -> String {
+    if event.reconciled {
+        format!("{}*", event.event_type)
+    } else {
+        format!("{}", event.event_type)
+    }
+}
+
+/// The size, permissions and modification time recorded with an event, as
+/// columns for `show --long`
+fn file_details(event: &Event) -> String {
+    let (size, mode, mtime) = match event.stat {
+        Some(ref stat) => (
+            format!("{}", stat.size),
+            stat.mode.map(|m| format!("{:04o}", m)).unwrap_or(String::from("-")),
+            format!("{}", stat.mtime),
+        ),
+        None => (String::from("-"), String::from("-"), String::from("-")),
+    };
+    format!("{: <10}{: <6}{: <20}", size, mode, mtime)
+}
+
 impl<'a, J> SnapshotViewer<J>
 where
     J: JournalReader<'a>,
 {
     fn show_relevant_snapshots(&'a self) -> Result<()> {
         let g = &self.glob;
-        println!(
-            "{: <5}{: <30}{: <30}{: <13}{}",
-            "Id",
-            "File Name",
-            "Timestamp",
-            "Update Type",
-            "Snapshot"
-        );
+        if self.long {
+            println!(
+                "{: <5}{: <30}{: <20}{: <13}{: <10}{: <6}{: <20}{}",
+                "Id",
+                "File Name",
+                "Timestamp",
+                "Update Type",
+                "Size",
+                "Mode",
+                "Modified",
+                "Content Hash"
+            );
+        } else {
+            println!(
+                "{: <5}{: <30}{: <30}{: <13}{}",
+                "Id",
+                "File Name",
+                "Timestamp",
+                "Update Type",
+                "Snapshot"
+            );
+        }
 
         let mut back = self.journal.backwards()?;
         for event in back.next_page()? {
             let event = event?;
             if let Some(matched_name) = match_name(&g, &event) {
                 let timestamp = format!("{}", event.timestamp);
-                let update_type = format!("{}", event.event_type);
-                let snapshot_path = event
-                    .snapshot
-                    .map(|p| String::from(p.to_string_lossy()))
-                    .unwrap_or(String::new());
+                let update_type = update_type(&event);
+                if self.long {
+                    let hash = match event.hash {
+                        Some(ref hash) if event.pruned => format!("{} (pruned)", hash),
+                        Some(ref hash) => hash.clone(),
+                        None => String::from("-"),
+                    };
+                    println!(
+                        "{: <5}{: <30}{: <20}{: <13}{}{}",
+                        event.event_id.unwrap(),
+                        matched_name,
+                        timestamp,
+                        update_type,
+                        file_details(&event),
+                        hash
+                    );
+                    continue;
+                }
+                let snapshot_path = match event.snapshot {
+                    Some(s) => format!("{}", s),
+                    None if event.pruned => String::from("(pruned)"),
+                    None => String::new(),
+                };
                 println!(
                     "{: <5}{: <30}{: <30}{: <13}{}",
                     event.event_id.unwrap(),
@@ -74,3 +137,236 @@ where
 
     }
 }
+
+pub fn show_log(watched_dir: &Path, stash_path: &Path, path: &Path) -> Result<()> {
+    let journal = sqlite_journal::new(&stash_path)?;
+    let store = snapshot_store::new(&stash_path);
+    let path = relative_path(&watched_dir, &path);
+
+    let lineage = file_lineage(&journal, &path)?;
+    if lineage.is_empty() {
+        println!("No history recorded for {}", path.to_string_lossy());
+        return Ok(());
+    }
+
+    // Work out how each version changed from the one before it, oldest first
+    let mut previous_lines = None;
+    let mut deltas = Vec::with_capacity(lineage.len());
+    for &(ref name, ref event) in lineage.iter().rev() {
+        let lines = line_count(&store, event);
+        let delta = match (previous_lines, lines) {
+            (Some(before), Some(after)) => format!("{:+}", after as i64 - before as i64),
+            (None, Some(_)) => String::from("new"),
+            _ => String::new(),
+        };
+        if event.event_type == EventType::Remove {
+            // Anything at the same path afterwards starts afresh
+            previous_lines = None;
+        } else if lines.is_some() {
+            previous_lines = lines;
+        }
+        deltas.push((name, event, lines, delta));
+    }
+
+    println!(
+        "{: <5}{: <40}{: <20}{: <13}{: <8}{}",
+        "Id",
+        "Path",
+        "Timestamp",
+        "Update Type",
+        "Lines",
+        "Change"
+    );
+    for (name, event, lines, delta) in deltas.into_iter().rev() {
+        println!(
+            "{: <5}{: <40}{: <20}{: <13}{: <8}{}",
+            event.event_id.unwrap(),
+            name.to_string_lossy(),
+            format!("{}", event.timestamp),
+            update_type(&event),
+            lines.map(|l| format!("{}", l)).unwrap_or(String::from("-")),
+            delta
+        );
+    }
+
+    Ok(())
+}
+
+/// Every event in the history of the file currently at `path`, newest first,
+/// following it back through any renames. Each event comes with the name the
+/// file had at that point.
+fn file_lineage<'a, J>(journal: &'a J, path: &Path) -> Result<Vec<(PathBuf, Event)>>
+where
+    J: JournalReader<'a>,
+{
+    let mut lineage = Vec::new();
+    let mut name = path.to_owned();
+
+    let mut back = journal.backwards()?;
+    for event in back.next_page()? {
+        let event = event?;
+
+        match event.event_type {
+            EventType::Rename => {
+                let renamed_from = match (&event.before_path, &event.after_path) {
+                    // Also covers a rename of one of the directories it's in
+                    (&Some(ref from), &Some(ref to)) if name.starts_with(to) => {
+                        match name.strip_prefix(to) {
+                            Ok(rest) if rest.as_os_str().is_empty() => Some(from.clone()),
+                            Ok(rest) => Some(from.join(rest)),
+                            Err(_) => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(renamed_from) = renamed_from {
+                    lineage.push((name, event));
+                    name = renamed_from;
+                }
+            }
+            // Including the removal of a directory it was in
+            EventType::Remove => {
+                if event.path().map_or(false, |removed| name.starts_with(removed)) {
+                    lineage.push((name.clone(), event));
+                }
+            }
+            _ => {
+                if event.path() == Some(&name) {
+                    lineage.push((name.clone(), event));
+                }
+            }
+        }
+    }
+
+    Ok(lineage)
+}
+
+fn line_count(store: &SnapshotStore, event: &Event) -> Option<usize> {
+    let contents = match event.snapshot {
+        Some(ref snapshot) => store.read(snapshot).ok()?,
+        None => return None,
+    };
+
+    if is_binary(&contents) {
+        None
+    } else {
+        Some(split_lines(&String::from_utf8_lossy(&contents)).len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use tempdir::TempDir;
+
+    use squirrel::event::*;
+    use squirrel::journal::{sqlite_journal, Journal};
+
+    fn p(path: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(path))
+    }
+
+    /// A journal of events a minute apart, each given as its type and the
+    /// paths before and after
+    fn journal_of(stash: &Path, events: Vec<(EventType, Option<PathBuf>, Option<PathBuf>)>) {
+        let mut journal = sqlite_journal::new(stash).unwrap();
+        for (n, (event_type, before, after)) in events.into_iter().enumerate() {
+            let timestamp = EventTime::parse(&format!("2017-10-21 20:{:02}", n)).unwrap();
+            journal.journal(new_event(event_type, timestamp, None, after, before)).unwrap();
+        }
+    }
+
+    /// The ids of the events in the file's history, newest first, with the
+    /// name it had at each
+    fn lineage(stash: &Path, path: &str) -> Vec<(EventId, String)> {
+        let journal = sqlite_journal::new(stash).unwrap();
+        super::file_lineage(&journal, Path::new(path))
+            .unwrap()
+            .into_iter()
+            .map(|(name, event)| (event.event_id.unwrap(), String::from(name.to_string_lossy())))
+            .collect()
+    }
+
+    fn names(history: &[(EventId, &str)]) -> Vec<(EventId, String)> {
+        history.iter().map(|&(id, name)| (id, String::from(name))).collect()
+    }
+
+    #[test]
+    fn history_is_limited_to_the_file() {
+        let stash = TempDir::new("squirrel-viewer").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, p("a.rs"), None),
+                (EventType::Create, p("b.rs"), None),
+                (EventType::Update, p("a.rs"), None),
+                (EventType::Update, p("a.rs.bak"), None),
+            ],
+        );
+
+        assert_eq!(lineage(stash.path(), "a.rs"), names(&[(3, "a.rs"), (1, "a.rs")]));
+    }
+
+    #[test]
+    fn history_is_followed_through_renames() {
+        let stash = TempDir::new("squirrel-viewer").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, p("old.rs"), None),
+                (EventType::Create, p("new.rs"), None),
+                (EventType::Remove, p("new.rs"), None),
+                (EventType::Rename, p("old.rs"), p("new.rs")),
+                (EventType::Update, p("new.rs"), None),
+            ],
+        );
+
+        assert_eq!(
+            lineage(stash.path(), "new.rs"),
+            names(&[(5, "new.rs"), (4, "new.rs"), (1, "old.rs")])
+        );
+    }
+
+    #[test]
+    fn history_is_followed_through_directory_renames() {
+        let stash = TempDir::new("squirrel-viewer").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, p("src/a.rs"), None),
+                (EventType::Create, p("src2/a.rs"), None),
+                (EventType::Rename, p("src"), p("lib")),
+                (EventType::Update, p("lib/a.rs"), None),
+                (EventType::Rename, p("lib"), p("lib2")),
+            ],
+        );
+
+        assert_eq!(
+            lineage(stash.path(), "lib2/a.rs"),
+            names(&[(5, "lib2/a.rs"), (4, "lib/a.rs"), (3, "lib/a.rs"), (1, "src/a.rs")])
+        );
+        // Only a directory can be renamed out from under a file, not a name
+        // that merely starts the same way
+        assert_eq!(lineage(stash.path(), "src2/a.rs"), names(&[(2, "src2/a.rs")]));
+    }
+
+    #[test]
+    fn history_carries_on_past_a_removal_and_recreation() {
+        let stash = TempDir::new("squirrel-viewer").unwrap();
+        journal_of(
+            stash.path(),
+            vec![
+                (EventType::Create, p("gen/out.rs"), None),
+                (EventType::Remove, p("gen"), None),
+                (EventType::Create, p("gen/out.rs"), None),
+                (EventType::Update, p("gen/out.rs"), None),
+            ],
+        );
+
+        assert_eq!(
+            lineage(stash.path(), "gen/out.rs"),
+            names(&[(4, "gen/out.rs"), (3, "gen/out.rs"), (2, "gen/out.rs"), (1, "gen/out.rs")])
+        );
+    }
+}
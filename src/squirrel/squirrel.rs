@@ -1,14 +1,47 @@
 
-use std::ffi::OsString;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::fs;
 
-use rand::{self, Rng};
+use config::Settings;
 
+use super::checkout::RecordedTree;
 use super::event::*;
 use super::journal;
+use super::snapshot_store::{self, SnapshotStore};
 use errors;
 
+/// What to do when a file is written without its contents changing, as
+/// editors often do on save, and `touch` always does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnchangedWrites {
+    /// Record nothing
+    Skip,
+    /// Record a `Touch` event, pointing at the snapshot already taken
+    Touch,
+}
+
+impl UnchangedWrites {
+    pub(crate) fn from_str(s: &str) -> Option<UnchangedWrites> {
+        if s == "skip" {
+            Some(UnchangedWrites::Skip)
+        } else if s == "touch" {
+            Some(UnchangedWrites::Touch)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for UnchangedWrites {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &UnchangedWrites::Skip => write!(f, "skip"),
+            &UnchangedWrites::Touch => write!(f, "touch"),
+        }
+    }
+}
 
 pub(crate) fn new<'a, Journal>(
     stash_path: &Path,
@@ -18,43 +51,107 @@ where
     Journal: journal::Journal,
 {
     Ok(Squirrel {
-        stash_path: stash_path.to_owned(),
+        store: snapshot_store::new(&stash_path),
         journal: journal,
+        latest_snapshots: HashMap::new(),
+        max_file_size: None,
+        unchanged_writes: UnchangedWrites::Skip,
     })
 }
 
+/// A `Squirrel` that snapshots files the way the settings say to
+pub(crate) fn configured<Journal>(settings: &Settings, journal: Journal) -> errors::Result<Squirrel<Journal>>
+where
+    Journal: journal::Journal,
+{
+    let mut squirrel = new(&settings.stash_path, journal)?;
+    squirrel.set_codec(settings.codec);
+    squirrel.set_keyframe_interval(settings.keyframe_interval);
+    squirrel.set_max_file_size(settings.max_file_size);
+    squirrel.set_unchanged_writes(settings.unchanged_writes);
+    Ok(squirrel)
+}
+
 pub(crate) struct Squirrel<Journal>
 where
     Journal: super::journal::Journal,
 {
-    stash_path: PathBuf,
+    store: SnapshotStore,
     journal: Journal,
-}
-
-fn snapshot_prefix() -> String {
-    let mut rng = rand::thread_rng();
-
-    rng
-        .gen_ascii_chars()
-        .take(21) // 6 bits per char * 21 ~= 122 bits of random, same as a GUIDv4
-        .collect()
+    // The last snapshot taken of each file, for new snapshots to be stored
+    // as deltas against
+    latest_snapshots: HashMap<PathBuf, Snapshot>,
+    max_file_size: Option<u64>,
+    unchanged_writes: UnchangedWrites,
 }
 
 impl<Journal> Squirrel<Journal>
 where
     Journal: super::journal::Journal,
 {
+    pub(crate) fn set_codec(&mut self, codec: Codec) {
+        self.store.set_codec(codec);
+    }
+
+    pub(crate) fn set_keyframe_interval(&mut self, keyframe_interval: usize) {
+        self.store.set_keyframe_interval(keyframe_interval);
+    }
+
+    pub(crate) fn set_max_file_size(&mut self, max_file_size: Option<u64>) {
+        self.max_file_size = max_file_size;
+    }
+
+    pub(crate) fn set_unchanged_writes(&mut self, unchanged_writes: UnchangedWrites) {
+        self.unchanged_writes = unchanged_writes;
+    }
+
     fn journal(&mut self, event: Event) -> errors::Result<()> {
         self.journal.journal(event)?;
         Ok(())
     }
 
-    fn save_snapshot(&self, source_file: &Path) -> errors::Result<PathBuf> {
-        let mut file_name = OsString::from(format!("{}-", snapshot_prefix()));
-        file_name.push(source_file.file_name().unwrap());
-        let stashed_file_name = self.stash_path.join(&file_name);
-        fs::copy(source_file, &stashed_file_name)?;
-        Ok(PathBuf::from(&file_name))
+    fn save_snapshot(&mut self, source_file: &Path) -> errors::Result<Snapshot> {
+        if let Some(max_file_size) = self.max_file_size {
+            let size = source_file.metadata()?.len();
+            if size > max_file_size {
+                return Err(
+                    errors::ErrorKind::FileTooLarge(source_file.to_string_lossy().into_owned(), size)
+                        .into(),
+                );
+            }
+        }
+
+        let snapshot = self.store.store(
+            source_file,
+            self.latest_snapshots.get(source_file),
+        )?;
+        self.latest_snapshots.insert(
+            source_file.to_owned(),
+            snapshot.clone(),
+        );
+        Ok(snapshot)
+    }
+
+    /// Files often vanish before they can be snapshotted, and big files are
+    /// skipped on purpose, so those are only worth a debug message. Anything
+    /// else is recorded as a failure.
+    fn snapshot_failed(&mut self, path: &Path, e: errors::Error) -> errors::Result<()> {
+        let expected = match e.kind() {
+            &errors::ErrorKind::Io(ref io) => io.kind() == io::ErrorKind::NotFound,
+            &errors::ErrorKind::FileTooLarge(..) => true,
+            _ => false,
+        };
+        if expected {
+            debug!("Unable to take snapshot of {}: {}", path.to_string_lossy(), e);
+            return Ok(());
+        }
+
+        warn!("Unable to take snapshot of {}: {}", path.to_string_lossy(), e);
+        self.journal.record_failure(
+            get_timestamp_now(),
+            Some(path),
+            &format!("unable to take a snapshot: {}", e),
+        )
     }
 
     fn on_write(&mut self, path: &Path) -> errors::Result<()> {
@@ -66,18 +163,42 @@ where
             return Ok(());
         }
 
-        let snapshot_path = self.save_snapshot(&path).ok();
-        if snapshot_path.is_none() {
-            debug!("Unable to take snapshot of {}", path.to_string_lossy());
-        }
+        let recorded = self.latest_snapshots.get(path).cloned();
+        let stat = file_stat(path);
+        let snapshot_path = match self.save_snapshot(&path) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                self.snapshot_failed(path, e)?;
+                None
+            }
+        };
 
-        self.journal(new_event(
+        let unchanged = match (&recorded, &snapshot_path) {
+            (&Some(ref recorded), &Some(ref snapshot)) => {
+                recorded.content_hash().is_some() && recorded.content_hash() == snapshot.content_hash()
+            }
+            _ => false,
+        };
+        let event_type = match (unchanged, self.unchanged_writes) {
+            (false, _) => event_type,
+            (true, UnchangedWrites::Touch) => EventType::Touch,
+            (true, UnchangedWrites::Skip) => {
+                debug!("{} is unchanged; not recording it", path.to_string_lossy());
+                return Ok(());
+            }
+        };
+
+        let mut event = new_event(
             event_type,
             get_timestamp_now(),
             snapshot_path,
             None,
             Some(path.to_owned()),
-        ))?;
+        );
+        if event.snapshot.is_some() {
+            event.stat = stat;
+        }
+        self.journal(event)?;
 
         Ok(())
     }
@@ -87,6 +208,7 @@ where
     }
 
     fn on_remove(&mut self, path: &Path) -> errors::Result<()> {
+        self.latest_snapshots.remove(path);
 
         self.journal(new_event(
             EventType::Remove,
@@ -99,26 +221,125 @@ where
     }
 
     fn on_rename(&mut self, source: &Path, destination: &Path) -> errors::Result<()> {
-
-        let destinatin_snap : errors::Result<PathBuf> = self.save_snapshot(&destination);
-        let success_snap = destinatin_snap.or_else(|_| self.save_snapshot(&source));
-        
-        let snapshot_path = success_snap.ok();
-        if snapshot_path.is_none() {
-            debug!("Unable to take snapshot after rename {} -> {}", source.to_string_lossy(), destination.to_string_lossy());
+        if let Some(previous) = self.latest_snapshots.remove(source) {
+            self.latest_snapshots.insert(destination.to_owned(), previous);
         }
 
-        self.journal(new_event(
+        let stat = file_stat(destination);
+        let snapshot_path = if destination.is_dir() {
+            None
+        } else {
+            match self.save_snapshot(&destination) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    self.snapshot_failed(destination, e)?;
+                    None
+                }
+            }
+        };
+
+        let mut event = new_event(
             EventType::Rename,
             get_timestamp_now(),
             snapshot_path,
             Some(destination.to_owned()),
             Some(source.to_owned()),
-        ))?;
+        );
+        if event.snapshot.is_some() {
+            event.stat = stat;
+        }
+        self.journal(event)?;
 
         Ok(())
     }
 
+    pub(crate) fn record_restore(&mut self, path: &Path, snapshot: &Snapshot) -> errors::Result<()> {
+        self.latest_snapshots.insert(path.to_owned(), snapshot.clone());
+        let mut event = new_event(
+            EventType::Restore,
+            get_timestamp_now(),
+            Some(snapshot.clone()),
+            None,
+            Some(path.to_owned()),
+        );
+        event.stat = file_stat(path);
+        self.journal(event)?;
+        Ok(())
+    }
+
+    /// Takes the snapshots the journal last recorded as the starting point
+    /// for new deltas, as if this daemon had taken them itself
+    pub(crate) fn remember_snapshots(&mut self, recorded: &RecordedTree) {
+        for (path, recorded) in recorded {
+            if let Some(ref snapshot) = recorded.snapshot {
+                self.latest_snapshots.insert(path.clone(), snapshot.clone());
+            }
+        }
+    }
+
+    /// Records a change found when the daemon starts up, rather than seen as
+    /// it happened: a `Baseline` for a file the journal has no snapshot of,
+    /// or a reconciled `Create`, `Update` or `Remove` for changes made while
+    /// the daemon wasn't running. If the contents turn out to be what the
+    /// journal already has, only a `Touch` with the file's new size and
+    /// modification time is recorded. Returns whether the contents changed.
+    pub(crate) fn record_reconciled(&mut self, path: &Path, event_type: EventType) -> errors::Result<bool> {
+        let reconciled = event_type != EventType::Baseline;
+
+        if event_type == EventType::Remove {
+            self.latest_snapshots.remove(path);
+            let mut event = new_event(event_type, get_timestamp_now(), None, None, Some(path.to_owned()));
+            event.reconciled = reconciled;
+            self.journal(event)?;
+            return Ok(true);
+        }
+
+        let recorded = self.latest_snapshots.get(path).cloned();
+        let stat = file_stat(path);
+        let snapshot = match self.save_snapshot(&path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.snapshot_failed(path, e)?;
+                return Ok(false);
+            }
+        };
+        if recorded.as_ref() == Some(&snapshot) {
+            // Only the size or modification time changed; keep them, so the
+            // file needn't be read again next time
+            if stat.is_some() {
+                let mut event = new_event(
+                    EventType::Touch,
+                    get_timestamp_now(),
+                    Some(snapshot),
+                    None,
+                    Some(path.to_owned()),
+                );
+                event.reconciled = true;
+                event.stat = stat;
+                self.journal(event)?;
+            }
+            return Ok(false);
+        }
+
+        let mut event = new_event(
+            event_type,
+            get_timestamp_now(),
+            Some(snapshot),
+            None,
+            Some(path.to_owned()),
+        );
+        event.reconciled = reconciled;
+        event.stat = stat;
+        self.journal(event)?;
+        Ok(true)
+    }
+
+    /// Records that something went wrong outside of any one snapshot, e.g. an
+    /// event that couldn't be handled at all
+    pub(crate) fn record_failure(&mut self, path: Option<&Path>, detail: &str) -> errors::Result<()> {
+        self.journal.record_failure(get_timestamp_now(), path, detail)
+    }
+
     pub(crate) fn dispatch_event(&mut self, event: FileEvent) -> errors::Result<()> {
 
         debug!("handling event : {:?}", event);
@@ -134,3 +355,124 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use squirrel::event::*;
+    use squirrel::journal::{sqlite_journal, JournalReader, PagedJournalQuery};
+    use super::UnchangedWrites;
+
+    fn write(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn recorded_types(stash: &Path) -> Vec<EventType> {
+        let journal = sqlite_journal::new(stash).unwrap();
+        let mut forwards = journal.forwards().unwrap();
+        forwards.next_page().unwrap().map(|e| e.unwrap().event_type).collect()
+    }
+
+    fn write_twice_then_change(unchanged_writes: UnchangedWrites) -> Vec<EventType> {
+        let stash = TempDir::new("squirrel").unwrap();
+        let stash = stash.path();
+        let file = stash.join("file.txt");
+
+        let mut squirrel = super::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        squirrel.set_unchanged_writes(unchanged_writes);
+        write(&file, b"contents");
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+        write(&file, b"contents");
+        squirrel.dispatch_event(FileEvent::Write(file.clone())).unwrap();
+        write(&file, b"changed");
+        squirrel.dispatch_event(FileEvent::Write(file.clone())).unwrap();
+
+        recorded_types(stash)
+    }
+
+    #[test]
+    fn unchanged_writes_can_be_skipped() {
+        assert_eq!(
+            write_twice_then_change(UnchangedWrites::Skip),
+            vec![EventType::Create, EventType::Update]
+        );
+    }
+
+    #[test]
+    fn unchanged_writes_can_be_recorded_as_touches() {
+        assert_eq!(
+            write_twice_then_change(UnchangedWrites::Touch),
+            vec![EventType::Create, EventType::Touch, EventType::Update]
+        );
+    }
+
+    #[test]
+    fn snapshots_that_fail_after_a_rename_are_recorded_as_failures() {
+        let stash = TempDir::new("squirrel").unwrap();
+        let stash = stash.path();
+        let file = stash.join("file.txt");
+        let moved = stash.join("moved.txt");
+
+        let mut squirrel = super::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        write(&file, b"contents");
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+
+        // Nothing can be stored once the object store is out of reach
+        fs::remove_dir_all(stash.join("objects")).unwrap();
+        write(&stash.join("objects"), b"");
+        fs::rename(&file, &moved).unwrap();
+        squirrel.dispatch_event(FileEvent::Rename(file.clone(), moved.clone())).unwrap();
+
+        let failures = sqlite_journal::new(stash).unwrap().failures().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, Some(moved));
+        assert_eq!(recorded_types(stash), vec![EventType::Create, EventType::Rename]);
+    }
+
+    #[test]
+    fn reconciled_files_whose_contents_are_unchanged_are_touched() {
+        let stash = TempDir::new("squirrel").unwrap();
+        let stash = stash.path();
+        let file = stash.join("file.txt");
+
+        let mut squirrel = super::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        write(&file, b"contents");
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+        write(&file, b"contents");
+
+        assert!(!squirrel.record_reconciled(&file, EventType::Update).unwrap());
+        assert_eq!(recorded_types(stash), vec![EventType::Create, EventType::Touch]);
+
+        let journal = sqlite_journal::new(stash).unwrap();
+        let mut back = journal.backwards().unwrap();
+        let touch = back.next_page().unwrap().next().unwrap().unwrap();
+        assert!(touch.reconciled);
+        assert_eq!(touch.stat, file_stat(&file));
+    }
+
+    #[test]
+    fn renames_keep_the_stat_of_the_renamed_file() {
+        let stash = TempDir::new("squirrel").unwrap();
+        let stash = stash.path();
+        let file = stash.join("file.txt");
+        let moved = stash.join("moved.txt");
+
+        let mut squirrel = super::new(stash, sqlite_journal::new(stash).unwrap()).unwrap();
+        write(&file, b"contents");
+        squirrel.dispatch_event(FileEvent::Create(file.clone())).unwrap();
+        fs::rename(&file, &moved).unwrap();
+        squirrel.dispatch_event(FileEvent::Rename(file.clone(), moved.clone())).unwrap();
+
+        let journal = sqlite_journal::new(stash).unwrap();
+        let mut back = journal.backwards().unwrap();
+        let rename = back.next_page().unwrap().next().unwrap().unwrap();
+        assert_eq!(rename.event_type, EventType::Rename);
+        assert_eq!(rename.stat, file_stat(&moved));
+        assert!(rename.stat.is_some());
+    }
+}